@@ -0,0 +1,21 @@
+import type { Handle } from "@sveltejs/kit";
+import { API, apiHeaders } from "$lib/server/pmrs";
+
+/** Only let visitors in if the pmrs API accepts their login (or doesn't need one). */
+export const handle: Handle = async ({ event, resolve }) => {
+	const check = await fetch(`${API}/services`, { headers: apiHeaders(event.request) }).catch(
+		() => null
+	);
+
+	if (check?.status === 401) {
+		return new Response("A pmrs login is needed", {
+			status: 401,
+			headers: { "WWW-Authenticate": 'Basic realm="pmrs"' }
+		});
+	}
+	if (!check?.ok) {
+		return new Response("The pmrs API can't be reached", { status: check?.status ?? 502 });
+	}
+
+	return resolve(event);
+};
@@ -0,0 +1,11 @@
+/** The pmrs API the dashboard is a front end to. */
+export const API = "http://localhost:8000/api/v1";
+
+/**
+ * Headers for requests to the pmrs API on behalf of a visitor: their own credentials, so they can
+ * only see what their login's scope allows. The dashboard has no credentials of its own.
+ */
+export const apiHeaders = (request: Request): HeadersInit => {
+	const authorization = request.headers.get("authorization");
+	return authorization ? { Authorization: authorization } : {};
+};
@@ -1,15 +1,18 @@
 import { json } from "@sveltejs/kit";
+import { API, apiHeaders } from "$lib/server/pmrs";
 
-export async function GET({ fetch, url }) {
+export async function GET({ fetch, request, url }) {
 	console.log(url);
 
 	try {
-		const payloadData = await fetch("http://localhost:8000/services");
-		const payload = await payloadData.json();
+		const payloadData = await fetch(`${API}/services`, {
+			headers: apiHeaders(request)
+		});
+		const body = await payloadData.json();
 
 		return json({
-			success: true,
-			payload
+			success: body.ok,
+			payload: body.data ?? null
 		});
 	} catch (_) {
 		return json({ success: false, payload: null });
@@ -1,11 +1,13 @@
 import { json } from "@sveltejs/kit";
+import { API, apiHeaders } from "$lib/server/pmrs";
 
-export async function GET({ fetch }) {
+export async function GET({ fetch, request }) {
 	try {
-		return json({
-			success: true,
-			payload: await fetch("http://localhost:8000/system").then((res) => res.json())
-		});
+		const body = await fetch(`${API}/system`, { headers: apiHeaders(request) }).then((res) =>
+			res.json()
+		);
+
+		return json({ success: body.ok, payload: body.data ?? null });
 	} catch (_) {
 		return json({ success: false, payload: null });
 	}
@@ -0,0 +1,234 @@
+use crate::settings::{AuthSettings, Credential};
+use crate::SETTINGS;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use serde::{Deserialize, Serialize};
+
+/// What a token or user may do through the API. Each scope includes the ones before it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,    // See services, their logs, output, events and crash reports.
+    Control, // Start, stop, restart and signal services, rebuild them and rotate their logs.
+    Admin,   // Add, change and remove services, and see the host's processes.
+}
+impl Scope {
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "control" => Some(Scope::Control),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Compare secrets in constant time, so how long a comparison takes doesn't give them away.
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+}
+
+fn find<'a>(
+    credentials: &'a [Credential],
+    name: Option<&str>,
+    secret: &str,
+) -> Option<&'a Credential> {
+    credentials
+        .iter()
+        .find(|c| same(&c.secret, secret) && name.is_none_or(|name| c.name == name))
+}
+
+/// The scope a request's credentials grant under `auth`, or `None` if it has no valid ones.
+///
+/// Credentials are a bearer token or a basic auth user's name and password in the `Authorization`
+/// header. Browsers can't set headers on websockets, so those may pass a token as `?token=`
+/// instead.
+fn scope(auth: &AuthSettings, request: &Request<'_>) -> Option<Scope> {
+    if let Some(header) = request.headers().get_one("Authorization") {
+        let (kind, credentials) = header.split_once(' ')?;
+        return match kind.to_lowercase().as_str() {
+            "bearer" => find(&auth.tokens, None, credentials.trim()).map(|c| c.scope),
+            "basic" => {
+                let decoded = BASE64.decode(credentials.trim()).ok()?;
+                let (user, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+                find(&auth.users, Some(user), password).map(|c| c.scope)
+            }
+            _ => None,
+        };
+    }
+
+    let websocket = request
+        .headers()
+        .get_one("Upgrade")
+        .is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+    if websocket {
+        let token = request.query_value::<&str>("token")?.ok()?;
+        return find(&auth.tokens, None, token).map(|c| c.scope);
+    }
+
+    None
+}
+
+fn authorize(request: &Request<'_>, required: Scope) -> Outcome<(), ()> {
+    if !SETTINGS.auth.enabled() {
+        return Outcome::Success(());
+    }
+
+    match scope(&SETTINGS.auth, request) {
+        Some(scope) if scope >= required => Outcome::Success(()),
+        Some(_) => Outcome::Error((Status::Forbidden, ())),
+        None => Outcome::Error((Status::Unauthorized, ())),
+    }
+}
+
+/// A request guard for the scope a request's credentials grant (or every scope, without auth).
+pub struct Granted(pub Scope);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Granted {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        if !SETTINGS.auth.enabled() {
+            return Outcome::Success(Granted(Scope::Admin));
+        }
+
+        match scope(&SETTINGS.auth, request) {
+            Some(scope) => Outcome::Success(Granted(scope)),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+macro_rules! scope_guard {
+    ($(#[$doc:meta])* $guard:ident, $scope:expr) => {
+        $(#[$doc])*
+        pub struct $guard;
+
+        #[rocket::async_trait]
+        impl<'r> FromRequest<'r> for $guard {
+            type Error = ();
+
+            async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+                authorize(request, $scope).map(|()| $guard)
+            }
+        }
+    };
+}
+
+scope_guard!(
+    /// A request guard for routes that need the `read` scope.
+    Read,
+    Scope::Read
+);
+scope_guard!(
+    /// A request guard for routes that need the `control` scope.
+    Control,
+    Scope::Control
+);
+scope_guard!(
+    /// A request guard for routes that need the `admin` scope.
+    Admin,
+    Scope::Admin
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+    use rocket::local::blocking::Client;
+
+    fn auth() -> AuthSettings {
+        AuthSettings::from_toml(
+            &r#"
+            tokens = [
+                { token = "reader", scope = "read" },
+                { name = "deploy", token = "controller", scope = "control" },
+            ]
+            users = [{ username = "root", password = "hunter2", scope = "admin" }]
+            "#
+            .parse()
+            .unwrap(),
+        )
+    }
+
+    /// The scope granted to a request for `uri` with the given headers.
+    fn granted(uri: &str, headers: &[(&'static str, String)]) -> Option<Scope> {
+        let client = Client::untracked(rocket::build()).unwrap();
+        let mut request = client.get(uri.to_owned());
+        for (name, value) in headers {
+            request.add_header(Header::new(*name, value.clone()));
+        }
+        scope(&auth(), request.inner())
+    }
+
+    fn basic(credentials: &str) -> (&'static str, String) {
+        (
+            "Authorization",
+            format!("Basic {}", BASE64.encode(credentials)),
+        )
+    }
+
+    #[test]
+    fn scopes_include_the_ones_before_them() {
+        assert!(Scope::Read < Scope::Control);
+        assert!(Scope::Control < Scope::Admin);
+        assert_eq!(Scope::parse("control"), Some(Scope::Control));
+        assert_eq!(Scope::parse("Admin"), None);
+    }
+
+    #[test]
+    fn bearer_tokens() {
+        let bearer = |token: &str| [("Authorization", format!("Bearer {token}"))];
+
+        assert_eq!(granted("/", &bearer("reader")), Some(Scope::Read));
+        assert_eq!(granted("/", &bearer("controller")), Some(Scope::Control));
+        assert_eq!(
+            granted("/", &[("Authorization", "bearer  reader ".into())]),
+            Some(Scope::Read)
+        );
+        assert_eq!(granted("/", &bearer("readers")), None);
+        // A user's password isn't a token.
+        assert_eq!(granted("/", &bearer("hunter2")), None);
+        assert_eq!(granted("/", &[]), None);
+    }
+
+    #[test]
+    fn basic_auth() {
+        assert_eq!(granted("/", &[basic("root:hunter2")]), Some(Scope::Admin));
+        assert_eq!(granted("/", &[basic("root:hunter3")]), None);
+        assert_eq!(granted("/", &[basic("admin:hunter2")]), None);
+        assert_eq!(granted("/", &[basic("root")]), None);
+        // Tokens aren't users, even named ones.
+        assert_eq!(granted("/", &[basic("deploy:controller")]), None);
+        assert_eq!(
+            granted("/", &[("Authorization", "Basic not base64!".into())]),
+            None
+        );
+        assert_eq!(
+            granted("/", &[("Authorization", "Digest root".into())]),
+            None
+        );
+    }
+
+    #[test]
+    fn query_tokens_only_for_websockets() {
+        let upgrade = || ("Upgrade", "websocket".to_owned());
+
+        assert_eq!(
+            granted("/ws?token=controller", &[upgrade()]),
+            Some(Scope::Control)
+        );
+        assert_eq!(granted("/ws?token=nope", &[upgrade()]), None);
+        assert_eq!(granted("/ws", &[upgrade()]), None);
+        assert_eq!(granted("/logs?token=controller", &[]), None);
+        // The header wins when both are given.
+        assert_eq!(
+            granted("/ws?token=controller", &[upgrade(), basic("root:hunter3")]),
+            None
+        );
+    }
+}
@@ -1,28 +1,48 @@
-use crate::PORT_CADDY;
+use crate::{PORT_CADDY, PORT_DASHBOARD_BUILD};
 use parking_lot::RwLock;
-use rocket::http::hyper::uri::Port;
+use std::collections::HashMap;
+use std::error::Error;
 use std::sync::Arc;
-use std::{collections::HashMap, io::{self, Write}};
+use std::thread;
+use tracing::{debug, error};
+use url::Url;
 
 use crate::services::Service;
 
-pub fn start() -> io::Result<()> {
+/// Parse a service's `proxy`: a domain and an optional path, without a protocol.
+pub fn parse_proxy(proxy: &str) -> Result<Url, String> {
+    if crate::HTTP_RE.is_match(proxy) {
+        return Err(format!(
+            "proxy {proxy} shouldn't contain the protocol (http:// or https://)"
+        ));
+    }
+
+    let url = Url::parse(&format!("http://{proxy}")).map_err(|e| format!("proxy {proxy}: {e}"))?;
+    if url.domain().is_none() {
+        return Err(format!("proxy {proxy} should start with a domain"));
+    }
+
+    Ok(url)
+}
+
+/// Load the Caddyfile in the background, at startup and again after services are added, changed
+/// or removed. A failure is logged, and leaves Caddy's current configuration as it was.
+pub fn reload() {
+    thread::spawn(|| {
+        if let Err(e) = start() {
+            error!(error = %e, "Failed to load the Caddyfile");
+        }
+    });
+}
+
+pub fn start() -> Result<(), Box<dyn Error + 'static>> {
     // For now, the configuation will be loaded in the Caddyfile format.
     // If you somehow understand Caddy's JSON configuation schema, please open a PR :P
     let mut services_by_domain: HashMap<String, Vec<Arc<RwLock<Service>>>> = HashMap::new();
-    for service in crate::SERVICES.iter() {
-        if let Some(ref mut proxy) = service.read().configuration.proxy.clone() {
-            if crate::HTTP_RE.find(&proxy).is_none() {
-                proxy.insert_str(0, "http://");
-            } else {
-                panic!("Proxies should not contain the protocol (http:// or https://)");
-            }
-
-            let url = url::Url::parse(&proxy).expect(&format!("failed to parse {proxy}"));
-            let domain = url
-                .domain()
-                .expect("the proxy should just be a domain")
-                .to_owned();
+    for service in crate::services::all().iter() {
+        if let Some(ref proxy) = service.read().configuration.proxy {
+            let url = parse_proxy(proxy)?;
+            let domain = url.domain().unwrap_or_default().to_owned();
 
             if let Some(t) = services_by_domain.get_mut(&domain) {
                 t.push(service.to_owned());
@@ -40,8 +60,7 @@ pub fn start() -> io::Result<()> {
         for service in services.iter() {
             let conf = &service.read().configuration;
             if let (Some(proxy), Some(port)) = (&conf.proxy, conf.port) {
-                let url =
-                    url::Url::parse(&format!("http://{proxy}")).expect("could not parse proxy url");
+                let url = parse_proxy(proxy)?;
                 let path = url.path();
 
                 // Use tab: <	>
@@ -55,10 +74,11 @@ pub fn start() -> io::Result<()> {
         }
 
         // Finally, add the dashboard
+        let dashboard = *PORT_DASHBOARD_BUILD;
         caddyfile.push_str(&format!(r#"
 	redir /admin /admin/ permanent
 	handle_path /admin/* {{
-		reverse_proxy localhost:3000
+		reverse_proxy localhost:{dashboard}
 	}}
 
 	handle {{
@@ -67,18 +87,20 @@ pub fn start() -> io::Result<()> {
 }}
 "#));
     }
-    println!("{caddyfile}"); 
+    debug!(%caddyfile, "Loading the Caddyfile");
     let client = reqwest::blocking::Client::new();
     
-    let upload_response = client.post("http://localhost:2019/load")
+    let upload_response = client.post(format!("http://localhost:{}/load", *PORT_CADDY))
         .header("Content-Type", "text/caddyfile")
         .body(caddyfile)
-        .send()
-        .expect("coud not send caddyfile")
-        .text()
-        .expect("i wanted text :(((");
+        .send()?;
+    let status = upload_response.status();
+    let upload_response = upload_response.text()?;
+    if !status.is_success() {
+        return Err(format!("Caddy refused the Caddyfile ({status}): {upload_response}").into());
+    }
 
-    println!("caddyresponse: {upload_response}");
+    debug!(response = %upload_response, "Loaded the Caddyfile");
 
     Ok(())
 }
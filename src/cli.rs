@@ -1,4 +1,6 @@
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, Duration, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -14,4 +16,152 @@ pub enum Command {
     Setup,
     Status,
     Daemonise,
+    /// Turn restarting a service when its files change on or off
+    Watch {
+        /// The service's name or id
+        service: String,
+        state: Toggle,
+    },
+    /// Run a service's build command, and restart it if the build succeeds
+    Rebuild {
+        /// The service's name or id
+        service: String,
+    },
+    /// Show or manage service logs
+    Logs(LogsArgs),
+    /// Add, change or remove services while pmrs is running
+    #[command(subcommand)]
+    Service(ServiceCommand),
+    /// List a service's crash reports, or show one
+    Crashes {
+        /// The service's name or id
+        service: String,
+        /// The report to show, or `latest`
+        report: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ServiceCommand {
+    /// Add a service and start it
+    Add {
+        /// The service's name
+        name: String,
+        #[command(flatten)]
+        definition: DefinitionArgs,
+    },
+    /// Change some of a service's definition, restarting it if it's running
+    Update {
+        /// The service's name or id
+        service: String,
+        #[command(flatten)]
+        definition: DefinitionArgs,
+    },
+    /// Stop a service and remove it
+    Remove {
+        /// The service's name or id
+        service: String,
+        /// Also remove the service from the config file
+        #[arg(long)]
+        persist: bool,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct DefinitionArgs {
+    /// Settings as they'd be written in the config file, like 'cmd = "npm start"'
+    pub settings: Vec<String>,
+    /// Read settings from a TOML file, before any given as arguments
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// Also save the change to the config file
+    #[arg(long)]
+    pub persist: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct LogsArgs {
+    #[command(subcommand)]
+    pub command: Option<LogsCommand>,
+    /// The services' names or ids. All services by default
+    pub services: Vec<String>,
+    /// Keep printing new lines as they're written
+    #[arg(short, long)]
+    pub follow: bool,
+    /// How many of the most recent lines to print
+    #[arg(short = 'n', long, default_value_t = 10)]
+    pub lines: usize,
+    /// Only show lines written since a time (RFC 3339), or a duration ago like 30s, 10m, 2h or 1d
+    #[arg(long, value_parser = parse_since)]
+    pub since: Option<DateTime<Utc>>,
+    /// Only show stderr
+    #[arg(long)]
+    pub stderr_only: bool,
+    /// Only show lines matching a regex
+    #[arg(long)]
+    pub grep: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum LogsCommand {
+    /// Rotate service logs now, regardless of their size or age
+    Rotate {
+        /// The services' names or ids. All services by default
+        services: Vec<String>,
+    },
+    /// Search service logs, including rotated ones
+    Search(SearchArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct SearchArgs {
+    /// A regex lines must match
+    pub pattern: String,
+    /// The services' names or ids. All services by default
+    pub services: Vec<String>,
+    /// Only show lines written since a time (RFC 3339), or a duration ago like 30s, 10m, 2h or 1d
+    #[arg(long, value_parser = parse_since)]
+    pub from: Option<DateTime<Utc>>,
+    /// Only show lines written until a time (RFC 3339), or a duration ago like 30s, 10m, 2h or 1d
+    #[arg(long, value_parser = parse_since)]
+    pub to: Option<DateTime<Utc>>,
+    /// Only show stderr
+    #[arg(long)]
+    pub stderr_only: bool,
+    /// Which page of results to show
+    #[arg(short, long, default_value_t = 1)]
+    pub page: usize,
+    /// How many results to show per page
+    #[arg(long, default_value_t = 100)]
+    pub per_page: usize,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Toggle {
+    On,
+    Off,
+}
+
+fn parse_since(since: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(since) {
+        return Ok(time.into());
+    }
+
+    let invalid = || format!("{since} isn't a time or a duration like 10m");
+    let (amount, unit) = since.split_at(
+        since
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?,
+    );
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    let ago = match unit {
+        "s" => Duration::seconds(amount),
+        "m" => Duration::minutes(amount),
+        "h" => Duration::hours(amount),
+        "d" => Duration::days(amount),
+        _ => return Err(invalid()),
+    };
+
+    Ok(Utc::now() - ago)
 }
@@ -0,0 +1,96 @@
+use crate::services::Service;
+use crate::settings::{self, AuthSettings};
+use crate::web::API_V1;
+use crate::{DEFAULT_CONFIG_PATH, PORT_ROCKET};
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use std::env;
+use std::io;
+
+lazy_static::lazy_static! {
+    /// The token requests are sent with: `PMRS_TOKEN`, or else the most capable one in the config
+    /// file (if it can be read).
+    static ref TOKEN: Option<String> = env::var("PMRS_TOKEN").ok().or_else(|| {
+        let config = settings::load(*DEFAULT_CONFIG_PATH).ok()?;
+        let auth = AuthSettings::from_toml(config.get("auth")?.as_table()?);
+        auth.strongest_token().map(|t| t.to_owned())
+    });
+}
+
+fn url(path: &str) -> String {
+    format!("http://localhost:{}{API_V1}{path}", *PORT_ROCKET)
+}
+
+fn request(client: Client, method: Method, path: &str) -> RequestBuilder {
+    let request = client.request(method, url(path));
+    match *TOKEN {
+        Some(ref token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+pub fn get(path: &str) -> RequestBuilder {
+    request(Client::new(), Method::GET, path)
+}
+
+pub fn post(path: &str) -> RequestBuilder {
+    request(Client::new(), Method::POST, path)
+}
+
+pub fn patch(path: &str) -> RequestBuilder {
+    request(Client::new(), Method::PATCH, path)
+}
+
+pub fn delete(path: &str) -> RequestBuilder {
+    request(Client::new(), Method::DELETE, path)
+}
+
+/// A GET request for a response that may stay open indefinitely (it won't time out).
+pub fn stream(path: &str) -> RequestBuilder {
+    let client = Client::builder().timeout(None).build().expect("a client");
+    request(client, Method::GET, path)
+}
+
+/// Send a request, turning connection failures and error statuses into `io::Error`s.
+///
+/// An error status' message is the one the daemon responded with, if it gave one.
+pub fn send(request: RequestBuilder) -> io::Result<reqwest::blocking::Response> {
+    let response = request
+        .send()
+        .map_err(|e| io::Error::other(format!("couldn't reach the pmrs daemon: {e}")))?;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return Ok(response);
+    }
+
+    let body: Option<serde_json::Value> = response.json().ok();
+    let message = body
+        .as_ref()
+        .and_then(|body| body.get("error")?.get("message")?.as_str())
+        .map(|e| e.to_owned())
+        .unwrap_or_else(|| status.to_string());
+    Err(io::Error::other(message))
+}
+
+/// Send a request and parse the `data` the daemon responded with.
+pub fn json<T: DeserializeOwned>(request: RequestBuilder) -> io::Result<T> {
+    let body: serde_json::Value = send(request)?.json().map_err(io::Error::other)?;
+    let data = body.get("data").cloned().unwrap_or_default();
+    serde_json::from_value(data).map_err(io::Error::other)
+}
+
+/// The services known to the daemon.
+pub fn services() -> io::Result<Vec<Service>> {
+    json(get("/services"))
+}
+
+/// Look up a service's id by its name (or id).
+pub fn service_id(service: &str) -> io::Result<usize> {
+    services()?
+        .iter()
+        .map(|s| &s.configuration)
+        .find(|c| c.name == service || c.id.to_string() == service)
+        .map(|c| c.id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no service {service}")))
+}
@@ -0,0 +1,215 @@
+use crate::logs::LogLine;
+use crate::redact::SECRETS;
+use crate::services::Service;
+use crate::SETTINGS;
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What a service's process used over its lifetime, from `wait4`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResourceUsage {
+    pub user_time_ms: u64,
+    pub system_time_ms: u64,
+    pub max_rss_kb: u64, // Peak resident memory.
+    pub minor_faults: u64,
+    pub major_faults: u64,
+    pub block_reads: u64,
+    pub block_writes: u64,
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+}
+impl From<libc::rusage> for ResourceUsage {
+    fn from(usage: libc::rusage) -> Self {
+        let ms = |t: libc::timeval| t.tv_sec as u64 * 1000 + t.tv_usec as u64 / 1000;
+
+        Self {
+            user_time_ms: ms(usage.ru_utime),
+            system_time_ms: ms(usage.ru_stime),
+            max_rss_kb: usage.ru_maxrss as u64,
+            minor_faults: usage.ru_minflt as u64,
+            major_faults: usage.ru_majflt as u64,
+            block_reads: usage.ru_inblock as u64,
+            block_writes: usage.ru_oublock as u64,
+            voluntary_switches: usage.ru_nvcsw as u64,
+            involuntary_switches: usage.ru_nivcsw as u64,
+        }
+    }
+}
+
+/// Wait for a child to exit like `Child::wait`, also collecting its resource usage.
+///
+/// Without `block`, returns `None` straight away if the child is still running.
+pub fn wait(child: &Child, block: bool) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    let options = if block { 0 } else { libc::WNOHANG };
+
+    loop {
+        match unsafe { libc::wait4(child.id() as i32, &mut status, options, &mut usage) } {
+            0 => return Ok(None),
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            _ => return Ok(Some((ExitStatus::from_raw(status), usage.into()))),
+        }
+    }
+}
+
+/// The environment a command will run with: pmrs' own, plus whatever was set on the command.
+pub fn environment(command: &Command) -> BTreeMap<String, String> {
+    let mut environment: BTreeMap<String, String> = env::vars().collect();
+    for (key, value) in command.get_envs() {
+        let key = key.to_string_lossy().to_string();
+        match value {
+            Some(value) => environment.insert(key, value.to_string_lossy().to_string()),
+            None => environment.remove(&key),
+        };
+    }
+
+    environment
+}
+
+/// Everything known about a service's process when it failed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CrashReport {
+    pub id: String,
+    pub service: String,
+    pub service_id: usize,
+    pub instance: usize, // Which process of the service crashed, counting from 1.
+    pub attempt: usize,
+    pub started: DateTime<Utc>,
+    pub exited: DateTime<Utc>,
+    pub runtime_ms: u128,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>, // The signal that killed the process, if one did.
+    pub core_dumped: bool,
+    pub cmd: String, // With secrets redacted, like `args`.
+    pub args: Vec<String>,
+    pub wd: PathBuf,
+    pub environment: BTreeMap<String, String>, // With secrets redacted.
+    pub resource_usage: ResourceUsage,
+    pub output: Vec<LogLine>, // The last lines the process printed.
+}
+impl CrashReport {
+    /// Put together a report of a service's process failing.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        s: &Arc<RwLock<Service>>,
+        instance: usize,
+        attempt: usize,
+        started: DateTime<Utc>,
+        runtime: Duration,
+        status: ExitStatus,
+        resource_usage: ResourceUsage,
+        environment: BTreeMap<String, String>,
+    ) -> Self {
+        let service = s.read();
+        let conf = &service.configuration;
+        let exited = Utc::now();
+        let (cmd, args) = SECRETS.command(&conf.cmd, &conf.args);
+
+        Self {
+            id: exited.format("%Y%m%dT%H%M%S%.3fZ").to_string(),
+            service: conf.name.clone(),
+            service_id: conf.id,
+            instance,
+            attempt,
+            started,
+            exited,
+            runtime_ms: runtime.as_millis(),
+            exit_code: status.code(),
+            signal: status.signal(),
+            core_dumped: status.core_dumped(),
+            cmd,
+            args,
+            wd: conf.wd.clone(),
+            environment: SECRETS.variables(environment),
+            resource_usage,
+            output: service.output.instance(instance, SETTINGS.crashes.lines),
+        }
+    }
+
+    /// Write the report to `<log_dir>/crashes/<service>/<id>.json`, and remove the oldest of the
+    /// service's reports past `crashes.retain`.
+    pub fn save(&self) -> io::Result<PathBuf> {
+        let dir = dir(&self.service);
+        if !dir.exists() {
+            DirBuilder::new().recursive(true).mode(0o750).create(&dir)?;
+        }
+
+        let path = dir.join(format!("{}.json", self.id));
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o640)
+            .open(&path)?
+            .write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+        for old in ids(&self.service)?.iter().skip(SETTINGS.crashes.retain) {
+            fs::remove_file(dir.join(format!("{old}.json")))?;
+        }
+
+        Ok(path)
+    }
+}
+
+fn dir(service: &str) -> PathBuf {
+    SETTINGS.log_dir.join("crashes").join(service)
+}
+
+/// The ids of a service's crash reports, newest first.
+fn ids(service: &str) -> io::Result<Vec<String>> {
+    let mut ids = match fs::read_dir(dir(service)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".json"))
+                    .map(|id| id.to_owned())
+            })
+            .collect::<Vec<String>>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    // Ids are timestamps, so they sort by time.
+    ids.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(ids)
+}
+
+/// A service's crash reports, newest first.
+pub fn list(service: &str) -> io::Result<Vec<CrashReport>> {
+    ids(service)?.iter().map(|id| read(service, id)).collect()
+}
+
+/// One of a service's crash reports, or `None` if there's no report with that id.
+pub fn get(service: &str, id: &str) -> io::Result<Option<CrashReport>> {
+    if !ids(service)?.iter().any(|i| i == id) {
+        return Ok(None);
+    }
+
+    read(service, id).map(Some)
+}
+
+fn read(service: &str, id: &str) -> io::Result<CrashReport> {
+    let report = fs::read_to_string(dir(service).join(format!("{id}.json")))?;
+    serde_json::from_str(&report).map_err(io::Error::other)
+}
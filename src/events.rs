@@ -0,0 +1,146 @@
+use crate::services::ServiceConfiguration;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rocket::tokio::sync::broadcast;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How many past events are kept for clients that connect later.
+const HISTORY: usize = 1000;
+
+lazy_static::lazy_static! {
+    static ref NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    /// When pmrs started, telling event ids from before a restart (which start over) apart.
+    static ref BOOT: i64 = Utc::now().timestamp_millis();
+    static ref RECENT: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::with_capacity(HISTORY));
+    static ref BUS: broadcast::Sender<Event> = broadcast::channel(HISTORY).0;
+}
+
+/// Something that happened to a service.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    pub id: u64, // Increases by one with every event.
+    pub time: DateTime<Utc>,
+    pub service: String,
+    pub service_id: usize,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The service's process was started.
+    Started { pid: u32, attempt: usize },
+    /// The service is ready for connections: its port is being listened on (straight away, if it
+    /// has no port or pmrs holds its socket).
+    Ready { port: Option<u16> },
+    /// The service's process exited.
+    Exited {
+        exit_code: Option<i32>,
+        signal: Option<i32>, // The signal that killed the process, if one did.
+    },
+    /// The service will be started again after a delay.
+    Restarting { delay_secs: u64 },
+    /// The service couldn't be built, started or waited on.
+    Errored { message: String },
+    /// The service stopped, and won't be started again until it's asked to be.
+    Stopped,
+    /// A log rule matched a line of the service's output.
+    RuleMatched { pattern: String, line: String },
+}
+
+/// Publish an event about a service to everyone subscribed, and keep it in the history.
+pub fn emit(service: &ServiceConfiguration, kind: EventKind) -> Event {
+    let event = Event {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        time: Utc::now(),
+        service: service.name.clone(),
+        service_id: service.id,
+        kind,
+    };
+
+    {
+        let mut recent = RECENT.lock();
+        if recent.len() >= HISTORY {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+    }
+
+    // It's fine for nobody to be listening.
+    let _ = BUS.send(event.clone());
+    event
+}
+
+/// An event's id as given to clients, unique across restarts of pmrs: `<boot>-<id>`.
+pub fn cursor(id: u64) -> String {
+    format!("{}-{id}", *BOOT)
+}
+
+/// The id of the last event a client with a cursor has seen, so it can be sent the ones after it.
+///
+/// Every event so far is new to a client with a cursor from before pmrs restarted (or from a
+/// bare id newer than any event yet).
+pub fn seen(cursor: &str) -> u64 {
+    let latest = NEXT_ID.load(Ordering::Relaxed) - 1;
+    let id = match cursor.trim().split_once('-') {
+        Some((boot, id)) if boot.parse() == Ok(*BOOT) => id.parse().ok(),
+        Some(_) => None,
+        None => cursor.trim().parse().ok(),
+    };
+
+    id.filter(|id| *id <= latest).unwrap_or(0)
+}
+
+/// Recent events, oldest first.
+pub fn recent() -> Vec<Event> {
+    RECENT.lock().iter().cloned().collect()
+}
+
+/// Receive events as they're emitted.
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    BUS.subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emitted() -> Event {
+        let definition = toml::Value::Table(toml::map::Map::from_iter([(
+            "cmd".to_owned(),
+            toml::Value::from("true"),
+        )]));
+        let service = ServiceConfiguration::try_from((&"events".to_owned(), &definition)).unwrap();
+        emit(&service, EventKind::Stopped)
+    }
+
+    #[test]
+    fn seen_takes_this_runs_cursors() {
+        let first = emitted();
+        let second = emitted();
+        assert!(second.id > first.id);
+
+        assert_eq!(seen(&cursor(first.id)), first.id);
+        assert_eq!(seen(&format!(" {} ", cursor(second.id))), second.id);
+        // Bare ids, as sent with `last_event_id`.
+        assert_eq!(seen(&first.id.to_string()), first.id);
+    }
+
+    #[test]
+    fn seen_sends_everything_to_other_cursors() {
+        let latest = emitted();
+
+        // From before a restart, when ids started over.
+        assert_eq!(seen(&format!("{}-{}", *BOOT - 1, latest.id)), 0);
+        // Newer than any event yet, so also from another run.
+        assert_eq!(seen(&cursor(u64::MAX)), 0);
+        assert_eq!(seen(&u64::MAX.to_string()), 0);
+
+        assert_eq!(seen(""), 0);
+        assert_eq!(seen("nonsense"), 0);
+        assert_eq!(seen(&format!("{}-", *BOOT)), 0);
+    }
+}
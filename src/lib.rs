@@ -1,13 +1,28 @@
+pub mod auth;
 pub mod caddy;
 pub mod cli;
+pub mod client;
+pub mod crashes;
+pub mod events;
+pub mod logging;
+pub mod logs;
+pub mod metrics;
+pub mod ports;
+pub mod redact;
+pub mod rules;
 pub mod services;
+pub mod settings;
+pub mod sockets;
+pub mod subscriptions;
 pub mod sysinfo_wrappers;
+pub mod syslog;
+pub mod watch;
 pub mod web;
 
-use crate::services::Service;
-use parking_lot::RwLock;
-use std::fs::File;
+use crate::services::{Service, Services};
+use crate::settings::Settings;
 use std::sync::{atomic::AtomicBool, Arc};
+use toml::Table;
 
 lazy_static::lazy_static! {
     pub static ref DEFAULT_CONFIG_PATH: &'static str = {
@@ -17,6 +32,13 @@ lazy_static::lazy_static! {
             "/etc/pmrs/pmrs.toml"
         }
     };
+    pub static ref DEFAULT_LOG_DIR: &'static str = {
+        if cfg!(debug_assertions) {
+            "./logs"
+        } else {
+            "/var/log/pmrs"
+        }
+    };
     pub static ref DASHBOARD_BUILD_PATH: &'static str = {
         if cfg!(debug_assertions) {
             "./dashboard/build/index.js"
@@ -25,12 +47,15 @@ lazy_static::lazy_static! {
         }
     };
 
-    pub static ref PORT_ROCKET: isize = 8000;
-    pub static ref PORT_DASHBOARD: isize = 5173;
-    pub static ref PORT_CADDY: isize = 2019;
+    pub static ref PORT_ROCKET: u16 = 8000;
+    pub static ref PORT_DASHBOARD: u16 = 5173;
+    pub static ref PORT_DASHBOARD_BUILD: u16 = 3000;
+    pub static ref PORT_CADDY: u16 = 2019;
 
     pub static ref RUNNING: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
-    pub static ref SERVICES: Arc<Vec<Arc<RwLock<Service>>>> = Service::init(File::open(*DEFAULT_CONFIG_PATH).expect("the config file")).expect("a valid service");
+    pub static ref CONFIG: Table = settings::load(*DEFAULT_CONFIG_PATH).expect("the config file");
+    pub static ref SETTINGS: Settings = Settings::from_toml(&CONFIG);
+    pub static ref SERVICES: Services = Service::init(&CONFIG).expect("a valid service");
 
     pub static ref HTTP_RE: regex::Regex = regex::Regex::new(r"^https?://").unwrap();
 }
@@ -0,0 +1,29 @@
+use crate::services::ServiceConfiguration;
+use crate::settings::DaemonLogFormat;
+use crate::SETTINGS;
+use std::io::{self, IsTerminal};
+use tracing::{info_span, Span};
+use tracing_subscriber::EnvFilter;
+
+/// Set up pmrs' own logging (as opposed to its services' logs), as configured by `log_level` and
+/// `log_format`.
+///
+/// The `PMRS_LOG` environment variable overrides `log_level`, and takes filters like
+/// `info,pmrs::watch=debug`. Text is coloured when written to a terminal.
+pub fn init() {
+    let filter =
+        EnvFilter::try_from_env("PMRS_LOG").unwrap_or_else(|_| EnvFilter::new(&SETTINGS.log_level));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stdout);
+
+    match SETTINGS.log_format {
+        DaemonLogFormat::Text => subscriber.with_ansi(io::stdout().is_terminal()).init(),
+        DaemonLogFormat::Json => subscriber.json().with_current_span(true).init(),
+    }
+}
+
+/// The span everything pmrs does for a service is logged in.
+pub fn span(service: &ServiceConfiguration) -> Span {
+    info_span!("service", service = %service.name, id = service.id)
+}
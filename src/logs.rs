@@ -0,0 +1,1120 @@
+use crate::rules::Rules;
+use crate::services::ServiceConfiguration;
+use crate::settings::{LogFormat, LogSettings};
+use crate::syslog::{Forwarder, Target};
+use crate::SETTINGS;
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use parking_lot::Mutex;
+use regex::Regex;
+use rocket::tokio::sync::broadcast;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+use tracing::error;
+
+/// How many lines a live follower can fall behind by before it starts missing them.
+const LIVE_CAPACITY: usize = 1024;
+
+lazy_static::lazy_static! {
+    /// Every log file pmrs is writing to, so they can be rotated on request.
+    static ref OPEN: Mutex<HashMap<PathBuf, Arc<Mutex<LogFile>>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+impl Stream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Where a service's stdout or stderr goes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Destination {
+    File(PathBuf), // Captured to a log file.
+    Null,          // Discarded.
+    Inherit,       // Passed straight through to pmrs' own stdout/stderr.
+    Syslog,        // Captured and sent to the local syslog daemon.
+}
+impl Destination {
+    /// Parse a `stdout`/`stderr` setting: `"null"`, `"inherit"`, `"syslog"`, or a file path
+    /// (relative to the log directory).
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "null" => Destination::Null,
+            "inherit" => Destination::Inherit,
+            "syslog" => Destination::Syslog,
+            path => Destination::File(SETTINGS.log_dir.join(path)),
+        }
+    }
+
+    /// The log file a service's stream is captured to when it isn't configured.
+    pub fn default_for(service: &str, stream: Stream) -> Self {
+        Destination::File(match (stream, SETTINGS.logs.merge) {
+            (Stream::Stdout, _) | (Stream::Stderr, true) => {
+                SETTINGS.log_dir.join(format!("{service}.log"))
+            }
+            (Stream::Stderr, false) => SETTINGS.log_dir.join(format!("{service}.error.log")),
+        })
+    }
+
+    /// How to set up the child's end of the stream, given whether pmrs captures it.
+    pub fn stdio(&self, captured: bool) -> Stdio {
+        match self {
+            Destination::Inherit => Stdio::inherit(),
+            _ if captured => Stdio::piped(),
+            _ => Stdio::null(),
+        }
+    }
+}
+
+/// Where captured lines are written.
+#[derive(Clone)]
+pub enum Sink {
+    File(Arc<Mutex<LogFile>>),
+    Forward(Forwarder),
+    Buffer(Arc<OutputBuffer>),
+    Rules(Arc<Rules>),
+}
+impl Sink {
+    /// Open everywhere a service's captured stdout and stderr are written.
+    ///
+    /// Both streams are forwarded to each of the service's `forward` targets, unless they're
+    /// inherited (and so never captured). Both are checked against the service's `rules`, even if
+    /// they're otherwise discarded. Whatever is captured is also kept in `buffer`.
+    pub fn open_all(
+        service: &ServiceConfiguration,
+        buffer: &Arc<OutputBuffer>,
+        rules: Option<&Arc<Rules>>,
+    ) -> io::Result<(Vec<Self>, Vec<Self>)> {
+        let mut forwarders: HashMap<Target, Forwarder> = HashMap::new();
+        let mut forwarder = |target: &Target| {
+            forwarders
+                .entry(target.clone())
+                .or_insert_with(|| {
+                    Forwarder::start(
+                        target.clone(),
+                        service.syslog_facility,
+                        service.syslog_tag.clone(),
+                    )
+                })
+                .clone()
+        };
+
+        let mut sinks = [Vec::new(), Vec::new()];
+        for (destination, sinks) in [&service.stdout, &service.stderr]
+            .into_iter()
+            .zip(&mut sinks)
+        {
+            match destination {
+                Destination::File(path) => sinks.push(Sink::File(open(&service.name, path)?)),
+                Destination::Syslog => sinks.push(Sink::Forward(forwarder(&Target::Local))),
+                Destination::Null => {}
+                Destination::Inherit => continue,
+            }
+
+            for target in service.forward.iter() {
+                if !(*destination == Destination::Syslog && *target == Target::Local) {
+                    sinks.push(Sink::Forward(forwarder(target)));
+                }
+            }
+
+            if let Some(rules) = rules {
+                sinks.push(Sink::Rules(rules.clone()));
+            }
+            if !sinks.is_empty() {
+                sinks.push(Sink::Buffer(buffer.clone()));
+            }
+        }
+
+        let [stdout, stderr] = sinks;
+        Ok((stdout, stderr))
+    }
+
+    fn write(&self, line: &LogLine) {
+        match self {
+            Sink::File(log) => {
+                let mut formatted = line.format(SETTINGS.logs.format);
+                formatted.push('\n');
+                if let Err(e) = log.lock().write_all(formatted.as_bytes()) {
+                    error!(service = line.service, "Couldn't write to a log file: {e}");
+                }
+            }
+            Sink::Forward(forwarder) => forwarder.send(line),
+            Sink::Buffer(buffer) => buffer.push(line.clone()),
+            Sink::Rules(rules) => rules.check(line),
+        }
+    }
+}
+
+/// A service's most recent output, kept in memory so it can be shown without reading log files.
+///
+/// New lines are also broadcast to anyone following the service's output live.
+#[derive(Debug)]
+pub struct OutputBuffer {
+    lines: Mutex<VecDeque<LogLine>>,
+    live: broadcast::Sender<LogLine>,
+}
+impl Default for OutputBuffer {
+    // Services deserialized by the CLI get one too, so this can't depend on the settings (which the
+    // CLI may not be able to read).
+    fn default() -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::new()),
+            live: broadcast::channel(LIVE_CAPACITY).0,
+        }
+    }
+}
+impl OutputBuffer {
+    pub fn push(&self, line: LogLine) {
+        {
+            let mut lines = self.lines.lock();
+            if lines.len() >= SETTINGS.logs.buffer_lines {
+                lines.pop_front();
+            }
+            lines.push_back(line.clone());
+        }
+
+        // It's fine for nobody to be listening.
+        let _ = self.live.send(line);
+    }
+
+    /// The last `n` buffered lines that pass the filter, oldest first.
+    pub fn recent(&self, filter: &LogFilter, n: usize) -> Vec<LogLine> {
+        let mut recent = self
+            .lines
+            .lock()
+            .iter()
+            .rev()
+            .filter(|line| filter.matches(line))
+            .take(n)
+            .cloned()
+            .collect::<Vec<LogLine>>();
+        recent.reverse();
+        recent
+    }
+
+    /// The last `n` buffered lines printed by one of the service's processes.
+    pub fn instance(&self, instance: usize, n: usize) -> Vec<LogLine> {
+        let mut lines = self
+            .lines
+            .lock()
+            .iter()
+            .rev()
+            .filter(|line| line.instance == instance)
+            .take(n)
+            .cloned()
+            .collect::<Vec<LogLine>>();
+        lines.reverse();
+        lines
+    }
+
+    /// Receive lines as they're captured.
+    pub fn subscribe(&self) -> broadcast::Receiver<LogLine> {
+        self.live.subscribe()
+    }
+}
+
+/// A line of captured service output.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogLine {
+    pub time: DateTime<Utc>,
+    pub stream: Stream,
+    pub service: String,
+    pub instance: usize, // Which process of the service printed the line, counting from 1.
+    pub line: String,
+}
+impl LogLine {
+    /// Format the line as it's written to log files.
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Plain => format!(
+                "{} {} [{}#{}] {}",
+                self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                self.stream.as_str(),
+                self.service,
+                self.instance,
+                self.line
+            ),
+            LogFormat::Json => serde_json::to_string(self).expect("a serialisable log line"),
+        }
+    }
+
+    /// Parse a line written by `format` (in either format) to one of `service`'s logs.
+    pub fn parse(text: &str, service: &str) -> Option<Self> {
+        if text.starts_with('{') {
+            return serde_json::from_str(text).ok();
+        }
+
+        let (time, rest) = text.split_once(' ')?;
+        let (stream, rest) = rest.split_once(' ')?;
+        let (instance, line) = rest
+            .strip_prefix(&format!("[{service}#"))?
+            .split_once("] ")?;
+
+        Some(Self {
+            time: DateTime::parse_from_rfc3339(time).ok()?.into(),
+            stream: match stream {
+                "stdout" => Stream::Stdout,
+                "stderr" => Stream::Stderr,
+                _ => return None,
+            },
+            service: service.to_owned(),
+            instance: instance.parse().ok()?,
+            line: line.to_owned(),
+        })
+    }
+}
+
+/// Which captured lines to show.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub stderr_only: bool,
+    pub grep: Option<Regex>,
+}
+impl LogFilter {
+    pub fn matches(&self, line: &LogLine) -> bool {
+        self.since.is_none_or(|since| line.time >= since)
+            && self.until.is_none_or(|until| line.time <= until)
+            && (!self.stderr_only || line.stream == Stream::Stderr)
+            && self.grep.as_ref().is_none_or(|re| re.is_match(&line.line))
+    }
+}
+
+/// A service log file that rotates itself according to the `[logs]` settings.
+///
+/// Output is written through pmrs rather than straight from the child, so a rotation happens
+/// between two writes and nothing written during it is lost.
+pub struct LogFile {
+    pub service: String,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    created: SystemTime, // When the file was started, which survives pmrs restarting.
+    settings: LogSettings,
+    compressing: Option<JoinHandle<()>>, // Gzipping the last rotated log, off the capture thread.
+}
+impl LogFile {
+    fn open(service: &str, path: &Path) -> io::Result<Self> {
+        Self::with_settings(service, path, SETTINGS.logs.clone())
+    }
+
+    fn with_settings(service: &str, path: &Path, settings: LogSettings) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o640)
+            .open(path)?;
+        let metadata = file.metadata()?;
+
+        Ok(Self {
+            service: service.to_owned(),
+            path: path.to_owned(),
+            size: metadata.len(),
+            // Not every filesystem records when a file was created.
+            created: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            file,
+            settings,
+            compressing: None,
+        })
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        let settings = &self.settings;
+
+        let too_big = settings
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + incoming as u64 > max_size);
+        let too_old = settings.rotate_every.is_some_and(|period| {
+            self.created
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= period)
+        });
+
+        too_big || too_old
+    }
+
+    /// The path of the `n`th most recent rotated log.
+    fn rotated_path(&self, n: usize, compressed: bool) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        if compressed {
+            path.push(".gz");
+        }
+        path.into()
+    }
+
+    /// Move the current log to `<log>.1`, shifting older logs up and dropping those past the
+    /// retention count, and start a fresh log.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        // The last rotated log has to be compressed before it's shifted up.
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+        let settings = self.settings.clone();
+
+        for compressed in [false, true] {
+            let _ = fs::remove_file(self.rotated_path(settings.retain, compressed));
+            for n in (1..settings.retain).rev() {
+                let from = self.rotated_path(n, compressed);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1, compressed))?;
+                }
+            }
+        }
+
+        if !self.path.exists() {
+            // Removed from under us; there's nothing to rotate.
+        } else if settings.retain > 0 {
+            fs::rename(&self.path, self.rotated_path(1, false))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        *self = Self::with_settings(&self.service, &self.path, settings)?;
+
+        if self.settings.compress && self.settings.retain > 0 {
+            let (from, to) = (self.rotated_path(1, false), self.rotated_path(1, true));
+            self.compressing = Some(thread::spawn(move || {
+                if let Err(e) = compress(&from, &to) {
+                    error!(log = %from.display(), error = %e, "Failed to compress a rotated log");
+                }
+            }));
+        }
+
+        Ok(())
+    }
+}
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Gzip `from` into `to`, removing `from`.
+///
+/// `to` only appears once it's complete, so nothing reads a partly written archive.
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut partial = to.to_owned().into_os_string();
+    partial.push(".partial");
+
+    let mut encoder = GzEncoder::new(File::create(&partial)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?;
+
+    fs::rename(&partial, to)?;
+    fs::remove_file(from)
+}
+
+/// Where a service's build output is logged.
+pub fn build_log_path(service: &str) -> PathBuf {
+    SETTINGS.log_dir.join(format!("{service}.build.log"))
+}
+
+/// Create the log directory, and the directories of any log files configured outside of it.
+pub fn prepare(services: &[ServiceConfiguration]) -> io::Result<()> {
+    let dirs = services
+        .iter()
+        .flat_map(paths)
+        .filter_map(|path| path.parent().map(|p| p.to_owned()))
+        .chain([SETTINGS.log_dir.clone()]);
+
+    for dir in dirs {
+        if !dir.exists() {
+            DirBuilder::new().recursive(true).mode(0o750).create(&dir)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The files a service's output is captured to.
+fn paths(service: &ServiceConfiguration) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = [&service.stdout, &service.stderr]
+        .into_iter()
+        .filter_map(|destination| match destination {
+            Destination::File(path) => Some(path.clone()),
+            _ => None,
+        })
+        .collect();
+    paths.dedup();
+    paths
+}
+
+/// The last `n` lines matching `filter` in the current logs of `services`, oldest first.
+pub fn tail(services: &[ServiceConfiguration], filter: &LogFilter, n: usize) -> Vec<LogLine> {
+    let mut lines: Vec<LogLine> = services
+        .iter()
+        .flat_map(|service| paths(service).into_iter().map(move |p| (&service.name, p)))
+        .filter_map(|(service, path)| last_lines(&path, service, filter, n).ok())
+        .flatten()
+        .collect();
+
+    lines.sort_by_key(|l| l.time);
+    lines.split_off(lines.len().saturating_sub(n))
+}
+
+/// How much of a log [`last_lines`] reads at a time.
+const TAIL_CHUNK: u64 = 8 * 1024;
+
+/// The last `n` lines matching `filter` in one log, oldest first, read backwards from its end
+/// so only as much of it is read as is needed.
+fn last_lines(
+    path: &Path,
+    service: &str,
+    filter: &LogFilter,
+    n: usize,
+) -> io::Result<Vec<LogLine>> {
+    let mut file = File::open(path)?;
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut partial = Vec::new(); // The start of a line whose beginning hasn't been read yet.
+    let mut lines = Vec::new(); // Newest first.
+
+    'chunks: while end > 0 && lines.len() < n {
+        let start = end.saturating_sub(TAIL_CHUNK);
+        let mut chunk = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.append(&mut partial);
+        end = start;
+
+        // Everything before the first newline may be the end of an earlier line.
+        let first = match start {
+            0 => 0,
+            _ => match chunk.iter().position(|&b| b == b'\n') {
+                Some(i) => i + 1,
+                None => {
+                    partial = chunk;
+                    continue;
+                }
+            },
+        };
+        partial = chunk[..first.saturating_sub(1)].to_vec();
+
+        for text in chunk[first..].split(|&b| b == b'\n').rev() {
+            let Some(line) = LogLine::parse(&String::from_utf8_lossy(text), service) else {
+                continue;
+            };
+            // Lines are written in order, so nothing before this one can match either.
+            if filter.since.is_some_and(|since| line.time < since) {
+                break 'chunks;
+            }
+            if filter.matches(&line) {
+                lines.push(line);
+                if lines.len() == n {
+                    break 'chunks;
+                }
+            }
+        }
+    }
+
+    lines.reverse();
+    Ok(lines)
+}
+
+/// A page of log search results.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResults {
+    pub lines: Vec<LogLine>,
+    pub page: usize, // Counting from 1.
+    pub per_page: usize,
+    pub total: usize, // How many lines matched, across every page.
+    pub pages: usize,
+}
+
+/// Search `services`' logs, including rotated (and compressed) ones, for lines matching
+/// `filter`, oldest first.
+///
+/// Every log is written in order, so they're merged as they're read, and only the lines on the
+/// requested page are kept.
+pub fn search(
+    services: &[ServiceConfiguration],
+    filter: &LogFilter,
+    page: usize,
+    per_page: usize,
+) -> io::Result<SearchResults> {
+    let mut logs = Vec::new();
+    for service in services {
+        for path in paths(service).iter().flat_map(|path| with_rotated(path)) {
+            // Nothing in a log last written to before the search starts can match.
+            let modified = fs::metadata(&path).and_then(|m| m.modified());
+            if let (Some(since), Ok(modified)) = (filter.since, modified) {
+                if DateTime::<Utc>::from(modified) < since {
+                    continue;
+                }
+            }
+
+            match matching_lines(&path, &service.name, filter) {
+                Ok(lines) => logs.push(lines),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // The next line of each log, and a heap of their times to take the earliest from.
+    let mut next: Vec<Option<LogLine>> = logs.iter_mut().map(Iterator::next).collect();
+    let mut earliest: BinaryHeap<Reverse<(DateTime<Utc>, usize)>> = next
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| Some(Reverse((line.as_ref()?.time, i))))
+        .collect();
+
+    let per_page = per_page.max(1);
+    let skip = page.saturating_sub(1) * per_page;
+    let mut lines = Vec::new();
+    let mut total = 0;
+    while let Some(Reverse((_, i))) = earliest.pop() {
+        let line = next[i].take().expect("a line for every log in the heap");
+        if total >= skip && lines.len() < per_page {
+            lines.push(line);
+        }
+        total += 1;
+
+        next[i] = logs[i].next();
+        if let Some(line) = &next[i] {
+            earliest.push(Reverse((line.time, i)));
+        }
+    }
+
+    Ok(SearchResults {
+        lines,
+        page,
+        per_page,
+        total,
+        pages: total.div_ceil(per_page),
+    })
+}
+
+/// The lines of a (possibly compressed) log matching `filter`, as they're read.
+///
+/// Lines that aren't valid UTF-8 are read lossily, and lines that can't be parsed are skipped.
+fn matching_lines(
+    path: &Path,
+    service: &str,
+    filter: &LogFilter,
+) -> io::Result<Box<dyn Iterator<Item = LogLine>>> {
+    let file = File::open(path)?;
+    let reader: Box<dyn BufRead> = match path.extension() {
+        Some(extension) if extension == "gz" => Box::new(BufReader::new(GzDecoder::new(file))),
+        _ => Box::new(BufReader::new(file)),
+    };
+
+    let service = service.to_owned();
+    let filter = filter.clone();
+    Ok(Box::new(
+        reader
+            .split(b'\n')
+            .map_while(Result::ok)
+            .filter_map(move |text| LogLine::parse(&String::from_utf8_lossy(&text), &service))
+            .filter(move |line| filter.matches(line)),
+    ))
+}
+
+/// A log and its rotated logs (`<log>.<n>`, or `<log>.<n>.gz`).
+fn with_rotated(path: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![path.to_owned()];
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return paths;
+    };
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let file_name = entry.file_name();
+        let rotated = file_name
+            .to_str()
+            .and_then(|f| f.strip_prefix(name))
+            .and_then(|f| f.strip_prefix('.'))
+            .map(|f| f.strip_suffix(".gz").unwrap_or(f))
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+
+        if rotated {
+            paths.push(entry.path());
+        }
+    }
+
+    paths
+}
+
+/// Picks up lines appended to services' logs since it was created (or last polled).
+pub struct Follower {
+    files: Vec<FollowedFile>,
+}
+struct FollowedFile {
+    service: String,
+    path: PathBuf,
+    identity: Option<(u64, u64)>, // The file's device and inode, to notice rotations.
+    offset: u64,
+    partial: Vec<u8>, // The start of a line that hasn't been completely written yet.
+}
+impl Follower {
+    pub fn new(services: &[ServiceConfiguration]) -> Self {
+        Self {
+            files: services
+                .iter()
+                .flat_map(|service| {
+                    paths(service).into_iter().map(|path| {
+                        let metadata = fs::metadata(&path).ok();
+                        FollowedFile {
+                            service: service.name.clone(),
+                            identity: metadata.as_ref().map(identity),
+                            offset: metadata.map(|m| m.len()).unwrap_or(0),
+                            path,
+                            partial: Vec::new(),
+                        }
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// New lines matching `filter`, oldest first.
+    pub fn poll(&mut self, filter: &LogFilter) -> Vec<LogLine> {
+        let mut lines = Vec::new();
+
+        for file in self.files.iter_mut() {
+            let Ok(mut f) = File::open(&file.path) else {
+                continue;
+            };
+            let Ok(metadata) = f.metadata() else {
+                continue;
+            };
+            if Some(identity(&metadata)) != file.identity || metadata.len() < file.offset {
+                // Rotated (or truncated); start from the top of the new file.
+                file.identity = Some(identity(&metadata));
+                file.offset = 0;
+                file.partial.clear();
+            }
+            if metadata.len() == file.offset {
+                continue;
+            }
+
+            let mut bytes = Vec::new();
+            if f.seek(SeekFrom::Start(file.offset)).is_err() || f.read_to_end(&mut bytes).is_err() {
+                continue;
+            }
+            file.offset += bytes.len() as u64;
+
+            file.partial.extend_from_slice(&bytes);
+            let complete = match file.partial.iter().rposition(|&b| b == b'\n') {
+                Some(end) => file.partial.drain(..=end).collect::<Vec<u8>>(),
+                None => continue,
+            };
+
+            lines.extend(
+                String::from_utf8_lossy(&complete)
+                    .lines()
+                    .filter_map(|l| LogLine::parse(l, &file.service))
+                    .filter(|l| filter.matches(l)),
+            );
+        }
+
+        lines.sort_by_key(|l| l.time);
+        lines
+    }
+}
+
+/// Which file a path currently leads to.
+fn identity(metadata: &fs::Metadata) -> (u64, u64) {
+    (metadata.dev(), metadata.ino())
+}
+
+/// Open a service's log file, sharing it with anything else already writing to it.
+pub fn open(service: &str, path: impl AsRef<Path>) -> io::Result<Arc<Mutex<LogFile>>> {
+    let path = path.as_ref();
+    let mut open = OPEN.lock();
+
+    if let Some(log) = open.get(path) {
+        return Ok(log.clone());
+    }
+
+    let log = Arc::new(Mutex::new(LogFile::open(service, path)?));
+    open.insert(path.to_owned(), log.clone());
+
+    Ok(log)
+}
+
+/// Rotate the open, non-empty logs of the given services (or every service if none are given).
+///
+/// Returns the paths of the logs that were rotated.
+pub fn rotate(services: &[String]) -> io::Result<Vec<PathBuf>> {
+    let logs: Vec<_> = OPEN.lock().values().cloned().collect();
+
+    let mut rotated = Vec::new();
+    for log in logs {
+        let mut log = log.lock();
+        if log.size > 0 && (services.is_empty() || services.contains(&log.service)) {
+            log.rotate()?;
+            rotated.push(log.path.clone());
+        }
+    }
+
+    Ok(rotated)
+}
+
+/// Copy a child's output into its sinks line by line until the child closes it, stamping each
+/// line with the time, stream, service and instance it came from.
+pub fn capture(
+    output: impl Read + Send + 'static,
+    stream: Stream,
+    service: &str,
+    instance: usize,
+    sinks: Vec<Sink>,
+) -> thread::JoinHandle<()> {
+    let service = service.to_owned();
+
+    thread::spawn(move || {
+        let mut output = BufReader::new(output);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match output.read_until(b'\n', &mut buf) {
+                Ok(0) => break,
+                Ok(_) => {
+                    let line = LogLine {
+                        time: Utc::now(),
+                        stream,
+                        service: service.clone(),
+                        instance,
+                        line: String::from_utf8_lossy(&buf)
+                            .trim_end_matches(['\n', '\r'])
+                            .to_owned(),
+                    };
+                    for sink in sinks.iter() {
+                        sink.write(&line);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(service: &str, stream: Stream) -> LogLine {
+        LogLine {
+            time: DateTime::parse_from_rfc3339("2026-10-18T19:04:23.139Z")
+                .unwrap()
+                .into(),
+            stream,
+            service: service.to_owned(),
+            instance: 2,
+            line: "GET /status] 200 {\"ok\": true}".to_owned(),
+        }
+    }
+
+    fn assert_round_trip(original: &LogLine, format: LogFormat) {
+        let parsed =
+            LogLine::parse(&original.format(format), &original.service).expect("a parsable line");
+
+        assert_eq!(parsed.time, original.time);
+        assert_eq!(parsed.stream, original.stream);
+        assert_eq!(parsed.service, original.service);
+        assert_eq!(parsed.instance, original.instance);
+        assert_eq!(parsed.line, original.line);
+    }
+
+    #[test]
+    fn plain_round_trip() {
+        assert_round_trip(&line("alpha", Stream::Stdout), LogFormat::Plain);
+        assert_round_trip(&line("beta svc", Stream::Stderr), LogFormat::Plain);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        assert_round_trip(&line("alpha", Stream::Stdout), LogFormat::Json);
+        assert_round_trip(&line("beta svc", Stream::Stderr), LogFormat::Json);
+    }
+
+    #[test]
+    fn plain_format() {
+        assert_eq!(
+            line("beta svc", Stream::Stderr).format(LogFormat::Plain),
+            "2026-10-18T19:04:23.139Z stderr [beta svc#2] GET /status] 200 {\"ok\": true}"
+        );
+    }
+
+    #[test]
+    fn parse_rejects_other_lines() {
+        let text = line("alpha", Stream::Stdout).format(LogFormat::Plain);
+
+        assert!(LogLine::parse(&text, "beta").is_none());
+        assert!(LogLine::parse("Traceback (most recent call last):", "alpha").is_none());
+        assert!(LogLine::parse("", "alpha").is_none());
+    }
+
+    /// An empty directory of its own for a test to write logs to.
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pmrs-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_retain_logs() {
+        let dir = temp_dir("rotate-size");
+        let path = dir.join("alpha.log");
+        let settings = LogSettings {
+            max_size: Some(6),
+            retain: 2,
+            ..Default::default()
+        };
+
+        let mut log = LogFile::with_settings("alpha", &path, settings).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "five\n");
+        assert_eq!(
+            fs::read_to_string(log.rotated_path(1, false)).unwrap(),
+            "four\n"
+        );
+        assert_eq!(
+            fs::read_to_string(log.rotated_path(2, false)).unwrap(),
+            "three\n"
+        );
+        assert!(!log.rotated_path(3, false).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compresses_rotated_logs() {
+        let dir = temp_dir("rotate-compress");
+        let path = dir.join("alpha.log");
+        let settings = LogSettings {
+            retain: 2,
+            compress: true,
+            ..Default::default()
+        };
+
+        let mut log = LogFile::with_settings("alpha", &path, settings).unwrap();
+        log.write_all(b"first\n").unwrap();
+        log.rotate().unwrap();
+        log.write_all(b"second\n").unwrap();
+        log.rotate().unwrap();
+        log.compressing.take().unwrap().join().unwrap();
+
+        let unzip = |n| {
+            let mut text = String::new();
+            GzDecoder::new(File::open(log.rotated_path(n, true)).unwrap())
+                .read_to_string(&mut text)
+                .unwrap();
+            text
+        };
+        assert_eq!(unzip(1), "second\n");
+        assert_eq!(unzip(2), "first\n");
+        assert!(!log.rotated_path(1, false).exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn age_survives_reopening() {
+        let dir = temp_dir("rotate-age");
+        let path = dir.join("alpha.log");
+        let settings = LogSettings {
+            rotate_every: Some(std::time::Duration::from_secs(3600)),
+            ..Default::default()
+        };
+
+        let first = LogFile::with_settings("alpha", &path, settings.clone()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let reopened = LogFile::with_settings("alpha", &path, settings).unwrap();
+
+        assert_eq!(reopened.created, first.created);
+        assert!(!reopened.needs_rotation(1));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn logged_at(service: &str, second: i64) -> LogLine {
+        LogLine {
+            time: DateTime::from_timestamp(1_700_000_000 + second, 0).unwrap(),
+            stream: Stream::Stdout,
+            service: service.to_owned(),
+            instance: 1,
+            line: format!("line {second}"),
+        }
+    }
+
+    fn logging_to(service: &str, path: &Path) -> ServiceConfiguration {
+        let definition = toml::Value::Table(toml::map::Map::from_iter([
+            ("cmd".to_owned(), toml::Value::from("true")),
+            (
+                "stdout".to_owned(),
+                toml::Value::from(path.to_str().unwrap()),
+            ),
+            ("stderr".to_owned(), toml::Value::from("null")),
+        ]));
+        ServiceConfiguration::try_from((&service.to_owned(), &definition)).unwrap()
+    }
+
+    #[test]
+    fn search_pages_through_every_log_in_order() {
+        let dir = temp_dir("search");
+        let (alpha, beta) = (dir.join("alpha.log"), dir.join("beta.log"));
+        let text = |service, seconds: &[i64], format| {
+            seconds
+                .iter()
+                .map(|&s| logged_at(service, s).format(format) + "\n")
+                .collect::<String>()
+        };
+
+        fs::write(&alpha, text("alpha", &[1, 3, 5], LogFormat::Plain)).unwrap();
+        let mut rotated = GzEncoder::new(
+            File::create(dir.join("alpha.log.1.gz")).unwrap(),
+            Compression::default(),
+        );
+        rotated
+            .write_all(text("alpha", &[0, 2], LogFormat::Plain).as_bytes())
+            .unwrap();
+        rotated.finish().unwrap();
+        let mut beta_text = text("beta", &[4], LogFormat::Json).into_bytes();
+        beta_text.extend_from_slice(b"not a log line \xff\n");
+        fs::write(&beta, beta_text).unwrap();
+
+        let services = [logging_to("alpha", &alpha), logging_to("beta", &beta)];
+        let filter = LogFilter::default();
+        let seconds = |results: &SearchResults| -> Vec<String> {
+            results.lines.iter().map(|l| l.line.clone()).collect()
+        };
+
+        let first = search(&services, &filter, 1, 4).unwrap();
+        assert_eq!(seconds(&first), ["line 0", "line 1", "line 2", "line 3"]);
+        assert_eq!((first.total, first.pages), (6, 2));
+
+        let second = search(&services, &filter, 2, 4).unwrap();
+        assert_eq!(seconds(&second), ["line 4", "line 5"]);
+        assert_eq!(second.lines[0].service, "beta");
+
+        let past = search(&services, &filter, 3, 4).unwrap();
+        assert!(past.lines.is_empty());
+        assert_eq!(past.total, 6);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn follows_across_rotations() {
+        let dir = temp_dir("follow");
+        let path = dir.join("alpha.log");
+        let text = |seconds: std::ops::Range<i64>| {
+            seconds
+                .map(|s| logged_at("alpha", s).format(LogFormat::Plain) + "\n")
+                .collect::<String>()
+        };
+        let followed =
+            |lines: Vec<LogLine>| -> Vec<String> { lines.into_iter().map(|l| l.line).collect() };
+
+        fs::write(&path, text(0..2)).unwrap();
+        let mut follower = Follower::new(&[logging_to("alpha", &path)]);
+        let filter = LogFilter::default();
+        assert!(follower.poll(&filter).is_empty());
+
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(text(2..3).as_bytes())
+            .unwrap();
+        assert_eq!(followed(follower.poll(&filter)), ["line 2"]);
+
+        // The new log is already longer than the old one was.
+        fs::rename(&path, dir.join("alpha.log.1")).unwrap();
+        fs::write(&path, text(3..8)).unwrap();
+        assert_eq!(
+            followed(follower.poll(&filter)),
+            ["line 3", "line 4", "line 5", "line 6", "line 7"]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn last_lines_reads_back_across_chunks() {
+        let dir = temp_dir("tail");
+        let path = dir.join("alpha.log");
+        // Lines of varying length, several chunks' worth, with multi-byte characters in them.
+        let lines: Vec<LogLine> = (0..2000)
+            .map(|s| LogLine {
+                line: format!(
+                    "{} é{}",
+                    if s % 3 == 0 { "odd" } else { "even" },
+                    "x".repeat(s as usize % 97)
+                ),
+                ..logged_at("alpha", s)
+            })
+            .collect();
+        let text: String = lines
+            .iter()
+            .map(|l| l.format(LogFormat::Plain) + "\n")
+            .collect();
+        assert!(text.len() as u64 > 4 * TAIL_CHUNK);
+        fs::write(&path, text).unwrap();
+
+        let times = |lines: &[LogLine]| lines.iter().map(|l| l.time).collect::<Vec<_>>();
+
+        let last = last_lines(&path, "alpha", &LogFilter::default(), 10).unwrap();
+        assert_eq!(times(&last), times(&lines[1990..]));
+        assert_eq!(last[9].line, lines[1999].line);
+
+        let odd = LogFilter {
+            grep: Some(Regex::new("^odd").unwrap()),
+            ..Default::default()
+        };
+        let expected: Vec<LogLine> = lines.iter().filter(|l| odd.matches(l)).cloned().collect();
+        let last = last_lines(&path, "alpha", &odd, 100).unwrap();
+        assert_eq!(times(&last), times(&expected[expected.len() - 100..]));
+
+        // Stops at `since`, and returns fewer lines when there aren't `n`.
+        let since = LogFilter {
+            since: Some(lines[1995].time),
+            ..Default::default()
+        };
+        let last = last_lines(&path, "alpha", &since, 10).unwrap();
+        assert_eq!(times(&last), times(&lines[1995..]));
+
+        let all = last_lines(&path, "alpha", &LogFilter::default(), 5000).unwrap();
+        assert_eq!(times(&all), times(&lines));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,15 +1,21 @@
 use clap::Parser;
-use color_print::cprintln;
+use color_print::{cformat, cprintln};
 use flack::lock_file;
-use pmrs::{caddy, cli, services::Service, SERVICES};
+use pmrs::crashes::CrashReport;
+use pmrs::logs::{LogLine, Stream};
+use pmrs::services::{self, Service};
+use pmrs::{caddy, cli, client, logs, watch, SETTINGS};
+use rocket::tokio::task::block_in_place;
 use std::{
     fs,
-    io::{self, Read, Write},
+    io::{self, BufRead, Read, Write},
     os::unix::{fs::PermissionsExt, net::UnixStream},
     process,
     sync::atomic::Ordering,
     thread,
+    time::Duration,
 };
+use tracing::{info, warn};
 
 #[rocket::main]
 async fn main() -> io::Result<()> {
@@ -18,22 +24,52 @@ async fn main() -> io::Result<()> {
         cli::Command::Setup => setup()?,
         cli::Command::Status => status()?,
         cli::Command::Daemonise => daemonise()?,
+        cli::Command::Watch { service, state } => block_in_place(|| set_watch(&service, state))?,
+        cli::Command::Rebuild { service } => block_in_place(|| rebuild(&service))?,
+        cli::Command::Crashes { service, report } => {
+            block_in_place(|| crashes(&service, report.as_deref()))?
+        }
+        cli::Command::Logs(args) => match args.command {
+            Some(cli::LogsCommand::Rotate { services }) => {
+                block_in_place(|| rotate_logs(&services))?
+            }
+            Some(cli::LogsCommand::Search(args)) => block_in_place(|| search_logs(&args))?,
+            None => block_in_place(|| logs(&args))?,
+        },
+        cli::Command::Service(command) => block_in_place(|| service(command))?,
     }
 
     Ok(())
 }
 
 fn start() -> io::Result<()> {
+    pmrs::logging::init();
+
     // Ensure this is the sole instance of pmrs running
     lock_file(&fs::File::open(*pmrs::DEFAULT_CONFIG_PATH)?)?;
 
+    let configurations: Vec<_> = services::all()
+        .iter()
+        .map(|s| s.read().configuration.clone())
+        .collect();
+    logs::prepare(&configurations)?;
+
     /* Start services */
     {
-        for service in SERVICES.iter() {
-            thread::spawn(|| Service::spawn(service.clone()));
+        for service in services::all().iter() {
+            Service::start(service);
+
+            if service.read().configuration.watch {
+                watch::set(service, true);
+            }
         }
     }
 
+    /* Metrics */
+    {
+        pmrs::metrics::start();
+    }
+
     /* Web Dashboard */
     {
         rocket::tokio::spawn(async move {
@@ -48,42 +84,44 @@ fn start() -> io::Result<()> {
 				let dash_log_options = dash_log_options.create(true).append(true);
 
 				let stdout = dash_log_options
-					.open("/var/log/pmrs/dashboard.log")
+					.open(SETTINGS.log_dir.join("dashboard.log"))
 					.expect("failed to open dashboard log file");
 				let stderr = dash_log_options
-					.open("/var/log/pmrs/dashboard.error.log")
+					.open(SETTINGS.log_dir.join("dashboard.error.log"))
 					.expect("failed to open dashboard error log file");
 
+                // The dashboard is given no token; it forwards each visitor's own login to the API.
                 std::process::Command::new("deno")
                     .arg("run")
                     .arg("--allow-env")
                     .arg("--allow-read")
                     .arg("--allow-net")
                     .arg(*pmrs::DASHBOARD_BUILD_PATH)
+                    .env("PORT", pmrs::PORT_DASHBOARD_BUILD.to_string())
 					.stdout(stdout)
 					.stderr(stderr)
                     .spawn()
                     .expect("failed to start web dashboard");
             });
         } else {
-            cprintln!("<yellow>Running in debug mode; skipping web dashboard startup</>");
+            warn!("Running in debug mode; skipping web dashboard startup");
         }
     }
 
     /* Caddy */
     {
-        thread::spawn(|| caddy::start());
+        caddy::reload();
     }
 
     /* Graceful shutdown */
     {
         ctrlc::set_handler(move || {
-            cprintln!("\n<red>Stopping</> <blue, bold>pmrs</>");
+            info!("Stopping pmrs");
             pmrs::RUNNING.store(false, Ordering::SeqCst);
             // Wait until all services are killed.
             // If the below panic occurs, it means the service was not killed, or there is a zombie ID.
             let mut i = 0;
-            while &SERVICES.iter().any(|s| s.read().running) == &true {
+            while services::all().iter().any(|s| s.read().running) {
                 thread::sleep(std::time::Duration::from_millis(100));
                 i += 1;
                 if i > 50 {
@@ -165,3 +203,285 @@ fn daemonise() -> io::Result<()> {
 
     Ok(())
 }
+
+fn set_watch(service: &str, state: cli::Toggle) -> io::Result<()> {
+    let id = client::service_id(service)?;
+    let enabled = matches!(state, cli::Toggle::On);
+    client::send(client::post(&format!("/services/{id}/watch?enabled={enabled}")))?;
+
+    match enabled {
+        true => cprintln!("<green>Watching</> <blue, bold>{service}</> for changes"),
+        false => cprintln!("<yellow>Stopped watching</> <blue, bold>{service}</>"),
+    }
+
+    Ok(())
+}
+
+fn rebuild(service: &str) -> io::Result<()> {
+    let id = client::service_id(service)?;
+    cprintln!("<green>Building</> <blue, bold>{service}</>");
+
+    // Builds can take a while
+    let request =
+        client::post(&format!("/services/{id}/rebuild")).timeout(Duration::from_secs(60 * 60));
+    let service: Service = client::json(request)?;
+
+    match service.last_build {
+        Some(build) if build.success => {
+            cprintln!(
+                "<green>Built</> <blue, bold>{}</> in {}ms",
+                service.configuration.name,
+                build.duration_ms
+            );
+        }
+        _ => {
+            let build_log = logs::build_log_path(&service.configuration.name);
+            cprintln!(
+                "<red>Build failed</>: <blue, bold>{}</> | <cyan>See {}</>",
+                service.configuration.name,
+                build_log.display()
+            );
+            process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+fn service(command: cli::ServiceCommand) -> io::Result<()> {
+    match command {
+        cli::ServiceCommand::Add { name, definition } => {
+            let mut body = read_definition(&definition)?;
+            body.insert("name".to_owned(), toml::Value::String(name.clone()));
+            let request = client::post("/services")
+                .query(&[("persist", definition.persist)])
+                .json(&body);
+            client::send(request)?;
+            cprintln!("<green>Added</> <blue, bold>{name}</>");
+        }
+        cli::ServiceCommand::Update {
+            service,
+            definition,
+        } => {
+            let id = client::service_id(&service)?;
+            let body = read_definition(&definition)?;
+            // Restarting the service can take a moment
+            let request = client::patch(&format!("/services/{id}"))
+                .query(&[("persist", definition.persist)])
+                .json(&body)
+                .timeout(Duration::from_secs(60));
+            client::send(request)?;
+            cprintln!("<green>Updated</> <blue, bold>{service}</>");
+        }
+        cli::ServiceCommand::Remove { service, persist } => {
+            let id = client::service_id(&service)?;
+            let request = client::delete(&format!("/services/{id}"))
+                .query(&[("persist", persist)])
+                .timeout(Duration::from_secs(60));
+            client::send(request)?;
+            cprintln!("<yellow>Removed</> <blue, bold>{service}</>");
+        }
+    }
+
+    Ok(())
+}
+
+/// A service definition from a file and/or settings given as arguments, the latter taking priority.
+fn read_definition(args: &cli::DefinitionArgs) -> io::Result<toml::Table> {
+    let parse = |definition: &str| -> io::Result<toml::Table> {
+        definition
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+
+    let mut definition = match args.file {
+        Some(ref file) => parse(&fs::read_to_string(file)?)?,
+        None => toml::Table::new(),
+    };
+    definition.extend(parse(&args.settings.join("\n"))?);
+
+    Ok(definition)
+}
+
+fn logs(args: &cli::LogsArgs) -> io::Result<()> {
+    let mut query = args
+        .services
+        .iter()
+        .map(|s| client::service_id(s).map(|id| ("service", id.to_string())))
+        .collect::<io::Result<Vec<(&str, String)>>>()?;
+    query.push(("n", args.lines.to_string()));
+    query.push(("follow", args.follow.to_string()));
+    query.push(("stderr_only", args.stderr_only.to_string()));
+    if let Some(since) = args.since {
+        query.push(("since", since.to_rfc3339()));
+    }
+    if let Some(ref grep) = args.grep {
+        query.push(("grep", grep.clone()));
+    }
+
+    let request = client::stream("/logs").query(&query);
+
+    for line in io::BufReader::new(client::send(request)?).lines() {
+        let line: LogLine = serde_json::from_str(&line?).map_err(io::Error::other)?;
+        print_log_line(&line);
+    }
+
+    Ok(())
+}
+
+fn search_logs(args: &cli::SearchArgs) -> io::Result<()> {
+    let mut query = args
+        .services
+        .iter()
+        .map(|s| client::service_id(s).map(|id| ("service", id.to_string())))
+        .collect::<io::Result<Vec<(&str, String)>>>()?;
+    query.push(("q", args.pattern.clone()));
+    query.push(("stderr_only", args.stderr_only.to_string()));
+    query.push(("page", args.page.to_string()));
+    query.push(("per_page", args.per_page.to_string()));
+    if let Some(from) = args.from {
+        query.push(("from", from.to_rfc3339()));
+    }
+    if let Some(to) = args.to {
+        query.push(("to", to.to_rfc3339()));
+    }
+
+    // Searching rotated logs can take a while
+    let request = client::get("/logs/search")
+        .query(&query)
+        .timeout(Duration::from_secs(5 * 60));
+    let results: logs::SearchResults = client::json(request)?;
+
+    for line in results.lines.iter() {
+        print_log_line(line);
+    }
+    cprintln!(
+        "<dim>Page {} of {} ({} matching lines)</>",
+        results.page,
+        results.pages.max(1),
+        results.total
+    );
+
+    Ok(())
+}
+
+fn print_log_line(line: &LogLine) {
+    let time = line.time.with_timezone(&chrono::Local).format("%F %T%.3f");
+    let service = service_name(&line.service);
+
+    match line.stream {
+        Stream::Stdout => cprintln!("<dim>{time}</> {service} | {}", line.line),
+        Stream::Stderr => cprintln!("<dim>{time}</> {service} <red>|</> <red>{}</>", line.line),
+    }
+}
+
+/// A service's name in its own colour, so merged logs are easy to tell apart.
+fn service_name(name: &str) -> String {
+    // FNV-1a, so a service keeps its colour from one run to the next.
+    let hash = name.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+
+    match hash % 6 {
+        0 => cformat!("<blue, bold>{name}</>"),
+        1 => cformat!("<green, bold>{name}</>"),
+        2 => cformat!("<yellow, bold>{name}</>"),
+        3 => cformat!("<magenta, bold>{name}</>"),
+        4 => cformat!("<cyan, bold>{name}</>"),
+        _ => cformat!("<bright-blue, bold>{name}</>"),
+    }
+}
+
+fn rotate_logs(services: &[String]) -> io::Result<()> {
+    let query = services
+        .iter()
+        .map(|s| client::service_id(s).map(|id| format!("service={id}")))
+        .collect::<io::Result<Vec<String>>>()?
+        .join("&");
+
+    let rotated: Vec<String> = client::json(client::post(&format!("/logs/rotate?{query}")))?;
+
+    for log in rotated.iter() {
+        cprintln!("<green>Rotated</> {log}");
+    }
+
+    Ok(())
+}
+
+fn crashes(service: &str, report: Option<&str>) -> io::Result<()> {
+    let id = client::service_id(service)?;
+    let reports: Vec<CrashReport> =
+        client::json(client::get(&format!("/services/{id}/crashes")))?;
+
+    let Some(report) = report else {
+        if reports.is_empty() {
+            cprintln!("<green>No crashes</> for <blue, bold>{service}</>");
+        }
+        for report in reports.iter() {
+            let exited = report.exited.with_timezone(&chrono::Local).format("%F %T");
+            let reason = exit_reason(report);
+            cprintln!(
+                "<yellow>{}</> <dim>{exited}</> {reason} after {}ms",
+                report.id,
+                report.runtime_ms
+            );
+        }
+        return Ok(());
+    };
+
+    let report = match report {
+        "latest" => reports.into_iter().next(),
+        report => reports.into_iter().find(|r| r.id == report),
+    }
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such crash report"))?;
+
+    let usage = &report.resource_usage;
+    let reason = exit_reason(&report);
+    let started = report.started.with_timezone(&chrono::Local).format("%F %T%.3f");
+    let exited = report.exited.with_timezone(&chrono::Local).format("%F %T%.3f");
+    cprintln!(
+        "<red>Crash</> <yellow>{}</>: <blue, bold>{}</> (instance {}, attempt {})",
+        report.id,
+        report.service,
+        report.instance,
+        report.attempt
+    );
+    cprintln!("  <bold>Status</>:  {reason}");
+    cprintln!(
+        "  <bold>Ran</>:     {started} to {exited} ({}ms)",
+        report.runtime_ms
+    );
+    let command = [&report.cmd].into_iter().chain(&report.args).cloned();
+    let (command, wd) = (command.collect::<Vec<_>>().join(" "), report.wd.display());
+    cprintln!("  <bold>Command</>: {command} <dim>(in {wd})</>");
+    cprintln!(
+        "  <bold>Usage</>:   {}ms user, {}ms system, {}KiB peak memory, {} major faults",
+        usage.user_time_ms,
+        usage.system_time_ms,
+        usage.max_rss_kb,
+        usage.major_faults
+    );
+
+    cprintln!("<bold>Environment</>:");
+    for (key, value) in report.environment.iter() {
+        cprintln!("  <cyan>{key}</>={value}");
+    }
+
+    cprintln!("<bold>Output</>:");
+    for line in report.output.iter() {
+        print_log_line(line);
+    }
+
+    Ok(())
+}
+
+fn exit_reason(report: &CrashReport) -> String {
+    match (report.exit_code, report.signal) {
+        (Some(code), _) => cformat!("<red>exited with {code}</>"),
+        (None, Some(signal)) if report.core_dumped => {
+            cformat!("<red>killed by signal {signal} (core dumped)</>")
+        }
+        (None, Some(signal)) => cformat!("<red>killed by signal {signal}</>"),
+        (None, None) => cformat!("<red>exited</>"),
+    }
+}
@@ -0,0 +1,309 @@
+use crate::services::{Service, ServiceState};
+use crate::sysinfo_wrappers;
+use crate::SETTINGS;
+use chrono::Utc;
+use parking_lot::RwLock;
+use std::fmt::{Display, Write};
+use std::sync::Arc;
+use std::thread;
+use sysinfo::{System, SystemExt};
+
+/// Every state a service can be in, with its name as exported.
+const STATES: [(ServiceState, &str); 5] = [
+    (ServiceState::Stopped, "stopped"),
+    (ServiceState::Running, "running"),
+    (ServiceState::Restarting, "restarting"),
+    (ServiceState::Idle, "idle"),
+    (ServiceState::Building, "building"),
+];
+
+lazy_static::lazy_static! {
+    static ref LATEST: RwLock<Option<Arc<sysinfo_wrappers::System>>> = RwLock::new(None);
+}
+
+/// Take the first sample of the host's metrics, then keep sampling them every
+/// `system.sample_interval` in the background.
+///
+/// CPU usage is measured between two samples, so the first one waits long enough for it to be
+/// meaningful.
+pub fn start() {
+    let interval = SETTINGS
+        .system
+        .sample_interval
+        .max(System::MINIMUM_CPU_UPDATE_INTERVAL);
+
+    let mut system = System::new_all();
+    thread::sleep(System::MINIMUM_CPU_UPDATE_INTERVAL);
+    sample(&mut system);
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        sample(&mut system);
+    });
+}
+
+fn sample(system: &mut System) {
+    system.refresh_cpu();
+    system.refresh_memory();
+    system.refresh_processes();
+    system.refresh_networks_list();
+    system.refresh_disks_list();
+    system.refresh_disks();
+    system.refresh_users_list();
+
+    *LATEST.write() = Some(Arc::new(sysinfo_wrappers::System::init(system)));
+}
+
+/// The latest sample of the host's metrics. Samples aren't changed once they're taken.
+pub fn latest() -> Arc<sysinfo_wrappers::System> {
+    LATEST
+        .read()
+        .clone()
+        .expect("the metrics sampler to have started")
+}
+
+/// Metrics in the Prometheus text exposition format.
+#[derive(Default)]
+struct Exposition(String);
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}\n# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+            .collect::<Vec<String>>()
+            .join(",");
+        let _ = match labels.is_empty() {
+            true => writeln!(self.0, "{name} {value}"),
+            false => writeln!(self.0, "{name}{{{labels}}} {value}"),
+        };
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Services' and the host's metrics, for Prometheus to scrape.
+///
+/// A service's CPU and memory usage covers its whole process tree, as of the latest sample.
+pub fn prometheus() -> String {
+    let system = latest();
+    let services: Vec<Service> = crate::services::all()
+        .iter()
+        .map(|s| s.read().clone())
+        .collect();
+    let ids: Vec<String> = services
+        .iter()
+        .map(|s| s.configuration.id.to_string())
+        .collect();
+    let labels = |i: usize| {
+        [
+            ("service", services[i].configuration.name.as_str()),
+            ("id", &ids[i]),
+        ]
+    };
+    let mut out = Exposition::default();
+
+    out.family(
+        "pmrs_service_up",
+        "gauge",
+        "Whether the service's process is running.",
+    );
+    for (i, s) in services.iter().enumerate() {
+        out.sample("pmrs_service_up", &labels(i), s.running as u8);
+    }
+
+    out.family(
+        "pmrs_service_state",
+        "gauge",
+        "The service's state (1 for the one it's in).",
+    );
+    for (i, s) in services.iter().enumerate() {
+        for (state, name) in STATES {
+            let [service, id] = labels(i);
+            let value = (s.state == state) as u8;
+            out.sample("pmrs_service_state", &[service, id, ("state", name)], value);
+        }
+    }
+
+    out.family(
+        "pmrs_service_healthy",
+        "gauge",
+        "Whether no log rule has marked the service unhealthy.",
+    );
+    for (i, s) in services.iter().enumerate() {
+        out.sample("pmrs_service_healthy", &labels(i), s.healthy as u8);
+    }
+
+    out.family(
+        "pmrs_service_restarts_total",
+        "counter",
+        "How many times the service has been restarted.",
+    );
+    for (i, s) in services.iter().enumerate() {
+        out.sample("pmrs_service_restarts_total", &labels(i), s.restarts);
+    }
+
+    out.family(
+        "pmrs_service_last_exit_code",
+        "gauge",
+        "The exit code of the service's last process to exit.",
+    );
+    for (i, s) in services.iter().enumerate() {
+        if let Some(code) = s.exit_code {
+            out.sample("pmrs_service_last_exit_code", &labels(i), code);
+        }
+    }
+
+    let now = Utc::now().timestamp().max(0) as u64;
+    let trees: Vec<Vec<&sysinfo_wrappers::Process>> = services
+        .iter()
+        .map(|s| {
+            s.pid
+                .map(|pid| system.process_tree(pid))
+                .unwrap_or_default()
+        })
+        .collect();
+
+    out.family(
+        "pmrs_service_uptime_seconds",
+        "gauge",
+        "How long the service's current process has been running.",
+    );
+    for (i, tree) in trees.iter().enumerate() {
+        if let Some(process) = tree.first() {
+            out.sample(
+                "pmrs_service_uptime_seconds",
+                &labels(i),
+                now.saturating_sub(process.start_time),
+            );
+        }
+    }
+
+    out.family(
+        "pmrs_service_cpu_usage_percent",
+        "gauge",
+        "CPU usage of the service's process tree, where 100 is one core.",
+    );
+    for (i, tree) in trees.iter().enumerate() {
+        let usage: f32 = tree.iter().map(|p| p.cpu_usage).sum();
+        out.sample("pmrs_service_cpu_usage_percent", &labels(i), usage);
+    }
+
+    out.family(
+        "pmrs_service_memory_bytes",
+        "gauge",
+        "Resident memory of the service's process tree.",
+    );
+    for (i, tree) in trees.iter().enumerate() {
+        let memory: u64 = tree.iter().map(|p| p.memory).sum();
+        out.sample("pmrs_service_memory_bytes", &labels(i), memory);
+    }
+
+    out.family(
+        "pmrs_service_processes",
+        "gauge",
+        "How many processes are in the service's process tree.",
+    );
+    for (i, tree) in trees.iter().enumerate() {
+        out.sample("pmrs_service_processes", &labels(i), tree.len());
+    }
+
+    for (name, help, value) in [
+        (
+            "pmrs_host_memory_total_bytes",
+            "Total memory.",
+            system.mem_total,
+        ),
+        (
+            "pmrs_host_memory_used_bytes",
+            "Used memory.",
+            system.mem_used,
+        ),
+        (
+            "pmrs_host_memory_free_bytes",
+            "Free memory.",
+            system.mem_free,
+        ),
+        (
+            "pmrs_host_memory_available_bytes",
+            "Memory available to start new processes with.",
+            system.mem_available,
+        ),
+        (
+            "pmrs_host_swap_total_bytes",
+            "Total swap.",
+            system.swap_total,
+        ),
+        ("pmrs_host_swap_used_bytes", "Used swap.", system.swap_used),
+        (
+            "pmrs_host_boot_time_seconds",
+            "When the host booted, as a Unix timestamp.",
+            system.boot_time,
+        ),
+        (
+            "pmrs_host_processes",
+            "How many processes are listed.",
+            system.process_list.len() as u64,
+        ),
+    ] {
+        out.family(name, "gauge", help);
+        out.sample(name, &[], value);
+    }
+
+    out.family(
+        "pmrs_host_cpu_usage_percent",
+        "gauge",
+        "CPU usage, of all CPUs or of one.",
+    );
+    out.sample(
+        "pmrs_host_cpu_usage_percent",
+        &[],
+        system.global_cpu.cpu_usage,
+    );
+    for cpu in system.cpus.iter() {
+        out.sample(
+            "pmrs_host_cpu_usage_percent",
+            &[("cpu", &cpu.name)],
+            cpu.cpu_usage,
+        );
+    }
+
+    out.family(
+        "pmrs_host_disk_total_bytes",
+        "gauge",
+        "The size of a mounted disk.",
+    );
+    for disk in system.disks.iter() {
+        let labels = [
+            ("disk", disk.name.as_str()),
+            ("mount_point", &disk.mount_point),
+        ];
+        out.sample("pmrs_host_disk_total_bytes", &labels, disk.total_space);
+    }
+    out.family(
+        "pmrs_host_disk_available_bytes",
+        "gauge",
+        "The space available on a mounted disk.",
+    );
+    for disk in system.disks.iter() {
+        let labels = [
+            ("disk", disk.name.as_str()),
+            ("mount_point", &disk.mount_point),
+        ];
+        out.sample(
+            "pmrs_host_disk_available_bytes",
+            &labels,
+            disk.available_space,
+        );
+    }
+
+    out.0
+}
@@ -0,0 +1,258 @@
+use crate::services::ServiceConfiguration;
+use crate::{PORT_CADDY, PORT_DASHBOARD, PORT_DASHBOARD_BUILD, PORT_ROCKET};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+
+/// Ports pmrs itself listens on (or talks to), which no service may use.
+fn reserved() -> HashMap<u16, String> {
+    HashMap::from([
+        (*PORT_ROCKET, "the pmrs API".to_owned()),
+        (*PORT_DASHBOARD, "the pmrs dashboard".to_owned()),
+        (*PORT_DASHBOARD_BUILD, "the pmrs dashboard".to_owned()),
+        (*PORT_CADDY, "the Caddy admin API".to_owned()),
+    ])
+}
+
+/// Whether nothing on the host is currently listening on `port`.
+pub fn is_free(port: u16) -> bool {
+    TcpListener::bind(("0.0.0.0", port)).is_ok()
+}
+
+/// Check the services' ports for conflicts, and assign a port to every `port = "auto"` service.
+///
+/// Fails if two services (or a service and pmrs itself) share a port, if a configured port is
+/// already in use on the host, or if the range has no free port left.
+pub fn allocate(
+    services: &mut [ServiceConfiguration],
+    range: &RangeInclusive<u16>,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let mut taken = reserved();
+
+    for s in services.iter().filter(|s| !s.auto_port) {
+        let Some(port) = s.port else { continue };
+
+        if let Some(owner) = taken.get(&port) {
+            return Err(format!(
+                "{} and {owner} are both configured to use port {port}",
+                s.name
+            )
+            .into());
+        }
+        if !is_free(port) {
+            return Err(format!("port {port} of {} is already in use", s.name).into());
+        }
+        taken.insert(port, s.name.clone());
+    }
+
+    for s in services.iter_mut().filter(|s| s.auto_port) {
+        let port = range
+            .clone()
+            .find(|p| !taken.contains_key(p) && is_free(*p))
+            .ok_or_else(|| {
+                format!(
+                    "no free port left in {}-{} for {}",
+                    range.start(),
+                    range.end(),
+                    s.name
+                )
+            })?;
+
+        s.port = Some(port);
+        taken.insert(port, s.name.clone());
+    }
+
+    Ok(())
+}
+
+/// Check a service being added at runtime for port conflicts with the others, and assign it a port
+/// if it's `port = "auto"`.
+///
+/// `current` is the port the service already has, if it's being reconfigured. It's kept for an
+/// `auto` service, and doesn't need to be free on the host since the service itself may hold it.
+pub fn claim(
+    service: &mut ServiceConfiguration,
+    others: &[ServiceConfiguration],
+    current: Option<u16>,
+    range: &RangeInclusive<u16>,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let mut taken = reserved();
+    taken.extend(
+        others
+            .iter()
+            .filter_map(|s| s.port.map(|port| (port, s.name.clone()))),
+    );
+
+    if service.auto_port {
+        service.port = current
+            .filter(|port| range.contains(port) && !taken.contains_key(port))
+            .or_else(|| {
+                range
+                    .clone()
+                    .find(|p| !taken.contains_key(p) && is_free(*p))
+            });
+        if service.port.is_none() {
+            return Err(format!(
+                "no free port left in {}-{} for {}",
+                range.start(),
+                range.end(),
+                service.name
+            )
+            .into());
+        }
+        return Ok(());
+    }
+
+    let Some(port) = service.port else {
+        return Ok(());
+    };
+    if let Some(owner) = taken.get(&port) {
+        return Err(format!(
+            "{} and {owner} are both configured to use port {port}",
+            service.name
+        )
+        .into());
+    }
+    if current != Some(port) && !is_free(port) {
+        return Err(format!("port {port} of {} is already in use", service.name).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str, port: toml::Value) -> ServiceConfiguration {
+        let definition = toml::Value::Table(toml::map::Map::from_iter([
+            ("cmd".to_owned(), toml::Value::from("true")),
+            ("port".to_owned(), port),
+        ]));
+        ServiceConfiguration::try_from((&name.to_owned(), &definition)).unwrap()
+    }
+
+    fn fixed(name: &str, port: u16) -> ServiceConfiguration {
+        service(name, toml::Value::from(i64::from(port)))
+    }
+
+    fn auto(name: &str) -> ServiceConfiguration {
+        service(name, toml::Value::from("auto"))
+    }
+
+    /// A port something on the host is listening on, for as long as the listener is kept.
+    fn in_use() -> (TcpListener, u16) {
+        let listener = TcpListener::bind(("0.0.0.0", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    #[test]
+    fn conflicting_services() {
+        let mut services = [fixed("a", 47301), fixed("b", 47301)];
+
+        let error = allocate(&mut services, &(47390..=47399)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "b and a are both configured to use port 47301"
+        );
+    }
+
+    #[test]
+    fn conflict_with_pmrs() {
+        let mut services = [fixed("a", *PORT_ROCKET)];
+
+        let error = allocate(&mut services, &(47390..=47399)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "a and the pmrs API are both configured to use port {}",
+                *PORT_ROCKET
+            )
+        );
+    }
+
+    #[test]
+    fn conflict_with_dashboard() {
+        let mut services = [fixed("a", *PORT_DASHBOARD_BUILD)];
+
+        let error = allocate(&mut services, &(47390..=47399)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "a and the pmrs dashboard are both configured to use port {}",
+                *PORT_DASHBOARD_BUILD
+            )
+        );
+    }
+
+    #[test]
+    fn port_in_use() {
+        let (_listener, port) = in_use();
+        let mut services = [fixed("a", port)];
+
+        let error = allocate(&mut services, &(47390..=47399)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("port {port} of a is already in use")
+        );
+    }
+
+    #[test]
+    fn auto_ports_skip_taken_ones() {
+        let mut services = [auto("b"), fixed("a", 47310), auto("c")];
+
+        allocate(&mut services, &(47310..=47319)).unwrap();
+        assert_eq!(
+            services.map(|s| s.port),
+            [Some(47311), Some(47310), Some(47312)]
+        );
+    }
+
+    #[test]
+    fn auto_ports_skip_ones_in_use() {
+        let (_listener, port) = in_use();
+        let mut services = [auto("a")];
+
+        let error = allocate(&mut services, &(port..=port)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("no free port left in {port}-{port} for a")
+        );
+    }
+
+    #[test]
+    fn claim_conflict() {
+        let others = [fixed("a", 47320)];
+        let mut service = fixed("b", 47320);
+
+        let error = claim(&mut service, &others, None, &(47390..=47399)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "b and a are both configured to use port 47320"
+        );
+    }
+
+    #[test]
+    fn claim_auto_port() {
+        let others = [fixed("a", 47330)];
+        let mut service = auto("b");
+
+        claim(&mut service, &others, None, &(47330..=47339)).unwrap();
+        assert_eq!(service.port, Some(47331));
+    }
+
+    #[test]
+    fn claim_keeps_current_port() {
+        // The service being reconfigured still holds its port.
+        let (_listener, port) = in_use();
+
+        let mut service = auto("a");
+        claim(&mut service, &[], Some(port), &(port..=port)).unwrap();
+        assert_eq!(service.port, Some(port));
+
+        let mut service = fixed("a", port);
+        claim(&mut service, &[], Some(port), &(47390..=47399)).unwrap();
+        assert_eq!(service.port, Some(port));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::SETTINGS;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::BTreeMap;
+
+pub const REDACTED: &str = "[redacted]";
+
+lazy_static::lazy_static! {
+    /// Names whose values are hidden, from the top-level `redact` setting.
+    pub static ref SECRETS: Redactor = Redactor::new(&SETTINGS.redact).expect("valid redact globs");
+}
+
+/// Hides the values of environment variables and command line options with secret-looking names.
+pub struct Redactor(GlobSet);
+impl Redactor {
+    /// Redact names matching any of the globs, ignoring case.
+    pub fn new(patterns: &[String]) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(GlobBuilder::new(pattern).case_insensitive(true).build()?);
+        }
+
+        Ok(Self(builder.build()?))
+    }
+
+    /// Whether a variable or option (without any leading dashes) is to be redacted.
+    pub fn is_secret(&self, name: &str) -> bool {
+        self.0.is_match(name.trim_start_matches('-'))
+    }
+
+    /// A variable's value, unless it's to be redacted.
+    pub fn value(&self, name: &str, value: &str) -> String {
+        match self.is_secret(name) {
+            true => REDACTED.to_owned(),
+            false => value.to_owned(),
+        }
+    }
+
+    /// `KEY=value` with its value hidden, if `KEY` is to be redacted.
+    pub fn assignment(&self, assignment: &str) -> Option<String> {
+        let (key, _) = assignment.split_once('=')?;
+        self.is_secret(key).then(|| format!("{key}={REDACTED}"))
+    }
+
+    /// An environment as `KEY=value` strings, with secrets hidden.
+    pub fn environ(&self, environ: &[String]) -> Vec<String> {
+        environ
+            .iter()
+            .map(|variable| {
+                self.assignment(variable)
+                    .unwrap_or_else(|| variable.clone())
+            })
+            .collect()
+    }
+
+    /// An environment as a map, with secrets hidden.
+    pub fn variables(&self, variables: BTreeMap<String, String>) -> BTreeMap<String, String> {
+        variables
+            .into_iter()
+            .map(|(key, value)| {
+                let value = self.value(&key, &value);
+                (key, value)
+            })
+            .collect()
+    }
+
+    /// A command line with the values of options like `--token=x`, `--token x` and `TOKEN=x`
+    /// hidden.
+    pub fn cmd(&self, cmd: &[String]) -> Vec<String> {
+        let mut redacted = Vec::with_capacity(cmd.len());
+        let mut hide_next = false;
+
+        for arg in cmd {
+            if hide_next {
+                redacted.push(REDACTED.to_owned());
+                hide_next = false;
+            } else if let Some(arg) = self.assignment(arg) {
+                redacted.push(arg);
+            } else {
+                hide_next = arg.starts_with('-') && self.is_secret(arg);
+                redacted.push(arg.clone());
+            }
+        }
+
+        redacted
+    }
+
+    /// A service's `cmd` and `args` with secrets hidden, treated as the one command line they're
+    /// run as: `cmd` is split on whitespace into the program and its first arguments.
+    pub fn command(&self, cmd: &str, args: &[String]) -> (String, Vec<String>) {
+        let words: Vec<String> = cmd
+            .split_whitespace()
+            .map(str::to_owned)
+            .chain(args.iter().cloned())
+            .collect();
+
+        let mut redacted = self.cmd(&words);
+        let args = redacted.split_off(cmd.split_whitespace().count());
+        (redacted.join(" "), args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redact(cmd: &[&str]) -> Vec<String> {
+        let redactor = Redactor::new(&["*TOKEN*".to_owned()]).unwrap();
+        redactor.cmd(&cmd.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn separate_option_value() {
+        assert_eq!(
+            redact(&["serve", "--token", "x", "--verbose"]),
+            ["serve", "--token", REDACTED, "--verbose"]
+        );
+        assert_eq!(redact(&["-token", "x"]), ["-token", REDACTED]);
+    }
+
+    #[test]
+    fn option_assignment() {
+        assert_eq!(
+            redact(&["serve", "--token=x"]),
+            ["serve", &format!("--token={REDACTED}")]
+        );
+    }
+
+    #[test]
+    fn variable_assignment() {
+        assert_eq!(
+            redact(&["TOKEN=x", "api_token=y", "serve"]),
+            [
+                &format!("TOKEN={REDACTED}"),
+                &format!("api_token={REDACTED}"),
+                "serve"
+            ]
+        );
+    }
+
+    #[test]
+    fn other_arguments_are_kept() {
+        let cmd = ["serve", "--port", "80", "PORT=80", "token"];
+        assert_eq!(redact(&cmd), cmd);
+    }
+
+    #[test]
+    fn service_command() {
+        let redactor = Redactor::new(&["*TOKEN*".to_owned()]).unwrap();
+
+        assert_eq!(
+            redactor.command("server --token abc --port 80", &[]),
+            (format!("server --token {REDACTED} --port 80"), vec![])
+        );
+        assert_eq!(
+            redactor.command("server --token", &["abc".to_owned(), "TOKEN=x".to_owned()]),
+            (
+                "server --token".to_owned(),
+                vec![REDACTED.to_owned(), format!("TOKEN={REDACTED}")]
+            )
+        );
+    }
+
+    #[test]
+    fn trailing_secret_option() {
+        assert_eq!(redact(&["serve", "--token"]), ["serve", "--token"]);
+    }
+}
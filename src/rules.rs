@@ -0,0 +1,158 @@
+use crate::events::{self, EventKind};
+use crate::logging;
+use crate::logs::{LogLine, Stream};
+use crate::services::Service;
+use crate::settings::{count, setting};
+use parking_lot::{Mutex, RwLock};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn, Span};
+
+/// How long a webhook may take to respond.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A pattern to look out for in a service's output, and what to do when it's printed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub pattern: String,        // A regex lines are matched against.
+    pub stream: Option<Stream>, // Only match lines from this stream. Both by default.
+    pub action: Action,
+    pub cooldown: u64, // Seconds after firing during which the rule won't fire again.
+}
+impl Rule {
+    pub fn from_toml(rule: &toml::Table) -> Result<Self, String> {
+        let pattern = setting(rule, "pattern", "a str", toml::Value::as_str)?
+            .ok_or("a rule needs a pattern")?
+            .to_owned();
+        Regex::new(&pattern).map_err(|e| format!("invalid rule pattern {pattern}: {e}"))?;
+
+        Ok(Self {
+            pattern,
+            stream: match setting(rule, "stream", "a str", toml::Value::as_str)? {
+                None => None,
+                Some("stdout") => Some(Stream::Stdout),
+                Some("stderr") => Some(Stream::Stderr),
+                Some(other) => return Err(format!("unknown stream {other}")),
+            },
+            action: match setting(rule, "action", "a str", toml::Value::as_str)?
+                .ok_or("a rule needs an action")?
+            {
+                "restart" => Action::Restart,
+                "unhealthy" => Action::Unhealthy,
+                "event" => Action::Event,
+                "webhook" => Action::Webhook(
+                    setting(rule, "url", "a str", toml::Value::as_str)?
+                        .ok_or("a webhook rule needs a url")?
+                        .to_owned(),
+                ),
+                other => return Err(format!("unknown rule action {other}")),
+            },
+            cooldown: setting(rule, "cooldown", "a number of seconds", count)?.unwrap_or(60),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Restart,         // Restart the service.
+    Unhealthy,       // Mark the service unhealthy until its next start.
+    Event,           // Emit an event.
+    Webhook(String), // Emit an event, and POST it to a URL as JSON.
+}
+
+/// A service's rules, checked against every line it prints.
+pub struct Rules {
+    service: Arc<RwLock<Service>>,
+    rules: Vec<(Rule, Regex, Mutex<Option<Instant>>)>, // With when each last fired.
+}
+impl Rules {
+    /// Compile a service's rules, or `None` if it has none.
+    pub fn new(s: &Arc<RwLock<Service>>) -> Option<Arc<Self>> {
+        let rules = s.read().configuration.rules.clone();
+        if rules.is_empty() {
+            return None;
+        }
+
+        Some(Arc::new(Self {
+            service: s.clone(),
+            rules: rules
+                .into_iter()
+                .map(|rule| {
+                    let regex = Regex::new(&rule.pattern).expect("a valid regex");
+                    (rule, regex, Mutex::new(None))
+                })
+                .collect(),
+        }))
+    }
+
+    pub fn check(&self, line: &LogLine) {
+        for (rule, regex, last_fired) in self.rules.iter() {
+            if rule.stream.is_some_and(|stream| stream != line.stream)
+                || !regex.is_match(&line.line)
+            {
+                continue;
+            }
+
+            {
+                let mut last_fired = last_fired.lock();
+                let cooldown = Duration::from_secs(rule.cooldown);
+                if last_fired.is_some_and(|fired| fired.elapsed() < cooldown) {
+                    continue;
+                }
+                *last_fired = Some(Instant::now());
+            }
+
+            self.fire(rule, line);
+        }
+    }
+
+    fn fire(&self, rule: &Rule, line: &LogLine) {
+        let conf = self.service.read().configuration.clone();
+        let _span = logging::span(&conf).entered();
+
+        let event = events::emit(
+            &conf,
+            EventKind::RuleMatched {
+                pattern: rule.pattern.clone(),
+                line: line.line.clone(),
+            },
+        );
+
+        match rule.action {
+            Action::Restart => {
+                info!(pattern = %rule.pattern, "Rule matched; restarting");
+                Service::restart(&self.service);
+            }
+            Action::Unhealthy => {
+                warn!(pattern = %rule.pattern, "Rule matched; marking unhealthy");
+                self.service.write().healthy = false;
+            }
+            Action::Event => {
+                info!(pattern = %rule.pattern, "Rule matched");
+            }
+            Action::Webhook(ref url) => {
+                info!(pattern = %rule.pattern, "Rule matched; calling {url}");
+
+                // Don't hold up the service's output on the webhook.
+                let url = url.clone();
+                let span = Span::current();
+                thread::spawn(move || {
+                    let _span = span.entered();
+                    let response = reqwest::blocking::Client::new()
+                        .post(&url)
+                        .json(&event)
+                        .timeout(WEBHOOK_TIMEOUT)
+                        .send()
+                        .and_then(|r| r.error_for_status());
+                    if let Err(e) = response {
+                        error!("Webhook to {url} failed: {e}");
+                    }
+                });
+            }
+        }
+    }
+}
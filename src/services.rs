@@ -1,18 +1,37 @@
-use crate::RUNNING;
-use color_print::{cformat, cprint, cprintln};
+use crate::crashes::{self, CrashReport};
+use crate::events::{self, EventKind};
+use crate::logging;
+use crate::logs::{self, Destination, OutputBuffer, Sink, Stream};
+use crate::redact::SECRETS;
+use crate::rules::{Rule, Rules};
+use crate::settings::{count, setting, strings};
+use crate::syslog::{self, Target};
+use crate::{caddy, ports, sockets, watch, CONFIG, RUNNING, SERVICES, SETTINGS};
+use chrono::Utc;
 use parking_lot::RwLock;
-use rocket::tokio::io::stdout;
 use serde::{Deserialize, Serialize};
-use std::env::{self, args};
-use std::fs::File;
-use std::io::Read;
-use std::io::Stdout;
+use std::env;
 use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
-use std::process::{Child, Command};
-use std::sync::atomic::Ordering;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use toml::Table;
+use tracing::{error, info, warn, Span};
+
+/// How long to wait for a crashed process' last output to be captured before reporting it.
+const CAPTURE_GRACE: Duration = Duration::from_secs(1);
+/// How long a service with a port may take to listen on it before it's reported as not ready.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    /// The id the next service added at runtime gets. Ids only go up, so a removed service's id
+    /// never comes to mean another service.
+    static ref NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServiceConfiguration {
@@ -26,11 +45,28 @@ pub struct ServiceConfiguration {
     pub restart_on_success: bool, // Whether or not to restart the service when it exits successfully.
     pub expo_backoff: bool, // Whether or not to use exponential backoff when restarting the service.
     pub proxy: Option<String>, // Proxy the service through this url root.
-    pub port: Option<u16>,
+    pub port: Option<u16>, // The port the service listens on. Assigned by pmrs when `auto_port` is set.
+    pub auto_port: bool, // Whether the port was configured as "auto" and picked from the port range.
+    pub socket: bool, // Whether pmrs binds the port itself and passes the socket to the service (LISTEN_FDS).
+    pub on_demand: bool, // Only start the service when a connection comes in. Implies `socket`.
+    pub idle_timeout: u64, // Seconds without connections after which an on-demand service is stopped.
+    pub watch: bool,       // Whether to restart the service when files in its watch paths change.
+    pub watch_paths: Vec<PathBuf>, // Paths to watch, relative to `wd`. The whole `wd` by default.
+    pub ignore: Vec<String>, // Globs (relative to `wd`) of paths whose changes are ignored.
+    pub watch_debounce: u64, // Milliseconds to wait for changes to settle before restarting.
+    pub build: Option<String>, // A shell command run in `wd` to build the service before it's started.
+    pub stdout: Destination, // Where the service's stdout goes. A file in the log directory by default.
+    pub stderr: Destination, // Where the service's stderr goes. A file in the log directory by default.
+    pub forward: Vec<Target>, // Where else captured output is sent: local syslog, or a remote endpoint.
+    pub syslog_facility: u8,  // The facility forwarded lines are logged under. `user` by default.
+    pub syslog_tag: String, // The app name forwarded lines are tagged with. The service's name by default.
+    pub rules: Vec<Rule>, // Patterns to look out for in the service's output, and what to do about them.
+    #[serde(skip)]
+    pub definition: toml::Table, // The definition this configuration was parsed from.
 }
 impl ServiceConfiguration {
-    pub fn from_toml(config: Table) -> Vec<Self> {
-        config
+    pub fn from_toml(config: &Table) -> Result<Vec<Self>, Box<dyn std::error::Error + 'static>> {
+        let mut services: Vec<Self> = config
             .get("services")
             .unwrap_or(&toml::Value::Array(vec![]))
             .as_table()
@@ -38,159 +74,586 @@ impl ServiceConfiguration {
             .iter()
             .enumerate()
             .map(|(idx, s)| {
-                let mut s: Self = s.into();
+                let mut s =
+                    Self::try_from(s).map_err(|e| format!("invalid definition of {}: {e}", s.0))?;
                 s.id = idx;
 
-                // Add global envs
-                config
-                    .get("envs")
-                    .unwrap()
-                    .as_table()
-                    .unwrap()
-                    .iter()
-                    .for_each(|(key, value)| {
-                        s.envs
-                            .push((key.to_owned(), value.as_str().expect("a str").to_owned()));
-                    });
-
-                s
+                s.envs.extend(global_envs(config));
+                Ok(s)
             })
-            .collect()
+            .collect::<Result<_, String>>()?;
+
+        ports::allocate(&mut services, &SETTINGS.port_range)?;
+
+        if let Some(s) = services.iter().find(|s| s.socket && s.port.is_none()) {
+            return Err(format!("{} uses socket activation but has no port", s.name).into());
+        }
+
+        Ok(services)
     }
+
+    /// Parse a service's definition, as it would be written under `[services.<name>]` in the config
+    /// file, for a service added or changed at runtime.
+    ///
+    /// The service's port is checked against (and, for `port = "auto"`, picked around) every other
+    /// service's, except `replacing`'s, which the new configuration takes the place (and id) of.
+    pub fn parse(
+        name: &str,
+        definition: &Table,
+        replacing: Option<&Arc<RwLock<Service>>>,
+    ) -> Result<Self, String> {
+        let name = name.to_owned();
+        let value = toml::Value::Table(definition.clone());
+        let mut s = Self::try_from((&name, &value))
+            .map_err(|e| format!("invalid definition of {name}: {e}"))?;
+        s.envs.extend(global_envs(&CONFIG));
+
+        let others: Vec<Self> = all()
+            .iter()
+            .filter(|o| !replacing.is_some_and(|r| Arc::ptr_eq(o, r)))
+            .map(|o| o.read().configuration.clone())
+            .collect();
+        if others.iter().any(|o| o.name == name) {
+            return Err(format!("there's already a service called {name}"));
+        }
+
+        let current = replacing.and_then(|r| r.read().configuration.port);
+        ports::claim(&mut s, &others, current, &SETTINGS.port_range).map_err(|e| e.to_string())?;
+        if s.socket && s.port.is_none() {
+            return Err(format!("{name} uses socket activation but has no port"));
+        }
+
+        s.id = match replacing {
+            Some(r) => r.read().configuration.id,
+            None => NEXT_ID.fetch_add(1, Ordering::SeqCst),
+        };
+        Ok(s)
+    }
+}
+
+/// The `envs` every service is given, from the top level of the config file.
+fn global_envs(config: &Table) -> Vec<(String, String)> {
+    config
+        .get("envs")
+        .map(|i| i.as_table().expect("a table"))
+        .unwrap_or(&toml::map::Map::new())
+        .iter()
+        .map(|(key, value)| (key.to_owned(), value.as_str().expect("a str").to_owned()))
+        .collect()
+}
+
+/// Check a service's name is safe to name files after: its logs and crash reports are.
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("a service needs a name".to_owned());
+    }
+    if name.starts_with('.') || name.contains('/') || name.chars().any(char::is_control) {
+        return Err(
+            "a service's name can't start with a dot, or contain slashes or control characters"
+                .to_owned(),
+        );
+    }
+
+    Ok(())
 }
 
 pub type ServiceConfigurationEntry<'a> = (&'a String, &'a toml::Value);
-impl Into<ServiceConfiguration> for ServiceConfigurationEntry<'_> {
-    fn into(self) -> ServiceConfiguration {
-        ServiceConfiguration {
+impl TryFrom<ServiceConfigurationEntry<'_>> for ServiceConfiguration {
+    type Error = String;
+
+    fn try_from(entry: ServiceConfigurationEntry<'_>) -> Result<Self, String> {
+        let name = entry.0;
+        check_name(name)?;
+        let t = entry.1.as_table().ok_or("a service should be a table")?;
+        let string = |key| setting(t, key, "a str", toml::Value::as_str);
+        let flag = |key| setting(t, key, "a bool", toml::Value::as_bool);
+
+        Ok(ServiceConfiguration {
             id: usize::MAX,
-            name: self.0.to_owned(),
-            args: self
-                .1
-                .get("args")
-                .map(|i| i.as_array().expect("an array"))
-                .unwrap_or(&vec![])
-                .iter()
-                .map(|i| i.as_str().expect("a str").to_owned())
-                .collect(),
-            envs: self
-                .1
-                .get("envs")
-                .map(|i| i.as_table().expect("a table"))
+            name: name.to_owned(),
+            args: setting(t, "args", "an array of strs", strings)?.unwrap_or_default(),
+            envs: setting(t, "envs", "a table", toml::Value::as_table)?
                 .unwrap_or(&toml::map::Map::new())
                 .iter()
-                .map(|(key, value)| (key.to_owned(), value.as_str().expect("a str").to_owned()))
-                .collect(),
-            wd: self
-                .1
-                .get("wd")
-                .map(|i| {
-                    PathBuf::from(i.as_str().expect("a str"))
-                        .canonicalize()
-                        .unwrap()
+                .map(|(key, value)| match value.as_str() {
+                    Some(value) => Ok((key.to_owned(), value.to_owned())),
+                    None => Err(format!("envs.{key} should be a str")),
                 })
-                .unwrap_or(env::current_dir().unwrap())
-                .to_owned()
-                .into(),
-            cmd: self
-                .1
-                .get("cmd")
-                .map(|x| x.as_str().expect("a str").to_owned())
-                .expect("a cmd key"),
-            max_restarts: self
-                .1
-                .get("max_restarts")
-                .map(|i| {
-                    Some(
-                        i.as_integer()
-                            .expect("the max restart count to be an integer")
-                            as usize,
-                    )
+                .collect::<Result<_, _>>()?,
+            wd: match string("wd")? {
+                Some(wd) => PathBuf::from(wd)
+                    .canonicalize()
+                    .map_err(|e| format!("wd {wd}: {e}"))?,
+                None => env::current_dir().map_err(|e| format!("the current directory: {e}"))?,
+            },
+            cmd: string("cmd")?.ok_or("a cmd is needed")?.to_owned(),
+            max_restarts: setting(t, "max_restarts", "a whole number", count)?
+                .map(|i| i as usize),
+            restart_on_success: flag("restart_on_success")?.unwrap_or(true),
+            expo_backoff: flag("expo_backoff")?.unwrap_or(false),
+            proxy: match string("proxy")? {
+                Some(proxy) => Some(caddy::parse_proxy(proxy).map(|_| proxy.to_owned())?),
+                None => None,
+            },
+            port: match t.get("port") {
+                Some(toml::Value::String(s)) if s == "auto" => None,
+                _ => setting(t, "port", "a port number or \"auto\"", |i| {
+                    u16::try_from(i.as_integer()?).ok()
+                })?,
+            },
+            auto_port: t.get("port").is_some_and(|i| i.as_str() == Some("auto")),
+            socket: match flag("socket")? {
+                Some(socket) => socket,
+                None => flag("on_demand")?.unwrap_or(false),
+            },
+            on_demand: flag("on_demand")?.unwrap_or(false),
+            idle_timeout: setting(t, "idle_timeout", "a number of seconds", count)?
+                .unwrap_or(600),
+            watch: flag("watch")?.unwrap_or(false),
+            watch_paths: setting(t, "watch_paths", "an array of strs", strings)?
+                .map(|paths| paths.iter().map(PathBuf::from).collect())
+                .unwrap_or(vec![PathBuf::from(".")]),
+            ignore: setting(t, "ignore", "an array of strs", strings)?.unwrap_or(
+                [
+                    ".git/**",
+                    "target/**",
+                    "node_modules/**",
+                    "logs/**",
+                    "*.log",
+                ]
+                .map(|i| i.to_owned())
+                .to_vec(),
+            ),
+            watch_debounce: setting(t, "watch_debounce", "a number of milliseconds", count)?
+                .unwrap_or(500),
+            build: string("build")?.map(str::to_owned),
+            stdout: string("stdout")?
+                .map(Destination::parse)
+                .unwrap_or(Destination::default_for(name, Stream::Stdout)),
+            stderr: string("stderr")?
+                .map(Destination::parse)
+                .unwrap_or(Destination::default_for(name, Stream::Stderr)),
+            forward: setting(t, "forward", "an array of strs", strings)?
+                .unwrap_or_default()
+                .iter()
+                .map(|target| {
+                    Target::parse(target).ok_or(format!(
+                        "unknown forward target {target}; it should be like \"syslog\", \"udp://host:514\" or \"tcp://host:601\""
+                    ))
                 })
-                .unwrap_or(None),
-            restart_on_success: self
-                .1
-                .get("restart_on_success")
-                .map(|i| i.as_bool().expect("a bool"))
-                .unwrap_or(true),
-            expo_backoff: self
-                .1
-                .get("expo_backoff")
-                .map(|i| i.as_bool().expect("a bool"))
-                .unwrap_or(false),
-            proxy: self
-                .1
-                .get("proxy")
-                .map(|i| i.as_str().expect("a str").to_owned()),
-            port: self
-                .1
-                .get("port")
-                .map(|i| i.as_integer().expect("a number") as u16),
-        }
+                .collect::<Result<_, _>>()?,
+            syslog_facility: match string("syslog_facility")? {
+                Some(facility) => syslog::facility(facility)
+                    .ok_or(format!("unknown syslog facility {facility}"))?,
+                None => 1,
+            },
+            syslog_tag: string("syslog_tag")?.unwrap_or(name).to_owned(),
+            rules: setting(t, "rules", "an array of tables", toml::Value::as_array)?
+                .unwrap_or(&Vec::new())
+                .iter()
+                .map(|r| Rule::from_toml(r.as_table().ok_or("a rule should be a table")?))
+                .collect::<Result<_, _>>()?,
+            definition: t.clone(),
+        })
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Stopped,    // Not running, and not going to be started again.
+    Running,    // The process is up.
+    Restarting, // The process exited and is waiting out its restart delay.
+    Idle,       // An on-demand service with no process, waiting for its first connection.
+    Building,   // Running the build command before the first start.
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Build {
+    pub success: bool,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u128,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Service {
     pub configuration: ServiceConfiguration,
+    pub state: ServiceState,
     pub running: bool,
     pub restarts: usize,
     pub exit_code: Option<i32>,
+    pub pid: Option<u32>,
+    pub healthy: bool, // Cleared by an `unhealthy` log rule, until the service next starts.
+    pub watching: bool, // Whether watch mode is currently on. Can be toggled at runtime.
+    pub building: bool,
+    pub last_build: Option<Build>,
+    #[serde(skip)]
+    pub output: Arc<OutputBuffer>, // The service's recent output, across restarts.
+    #[serde(skip)]
+    pub watcher_started: bool,
+    #[serde(skip)]
+    pub restart_requested: bool,
+    #[serde(skip)]
+    pub stop_requested: bool,
 }
 impl From<ServiceConfiguration> for Service {
     fn from(configuration: ServiceConfiguration) -> Self {
         Self {
+            watching: configuration.watch,
             configuration,
+            state: ServiceState::Stopped,
             running: false,
             restarts: 0,
             exit_code: None,
+            pid: None,
+            healthy: true,
+            building: false,
+            last_build: None,
+            output: Arc::new(OutputBuffer::default()),
+            watcher_started: false,
+            restart_requested: false,
+            stop_requested: false,
         }
     }
 }
+
+pub type Services = Arc<RwLock<Vec<Arc<RwLock<Service>>>>>;
+
+/// Look up a signal by its name (like `SIGHUP` or `HUP`) or number.
+pub fn signal_number(signal: &str) -> Option<i32> {
+    if let Ok(number) = signal.parse::<i32>() {
+        return (1..=64).contains(&number).then_some(number);
+    }
+
+    let name = signal.to_uppercase();
+    Some(match name.strip_prefix("SIG").unwrap_or(&name) {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "TSTP" => libc::SIGTSTP,
+        "WINCH" => libc::SIGWINCH,
+        _ => return None,
+    })
+}
+
+/// Every service pmrs manages, as of now.
+pub fn all() -> Vec<Arc<RwLock<Service>>> {
+    SERVICES.read().clone()
+}
+
+/// The service with an id, if there is one.
+pub fn find(id: usize) -> Option<Arc<RwLock<Service>>> {
+    SERVICES
+        .read()
+        .iter()
+        .find(|s| s.read().configuration.id == id)
+        .cloned()
+}
+
 impl Service {
-    pub fn init(
-        mut config_file: File,
-    ) -> Result<Arc<Vec<Arc<RwLock<Self>>>>, Box<dyn std::error::Error + 'static>> {
-        let mut config_file_buffer = Vec::new();
-        config_file.read_to_end(&mut config_file_buffer)?;
-        let config: Table = String::from_utf8_lossy(&config_file_buffer).parse()?;
-
-        let services: Vec<Arc<RwLock<Service>>> = ServiceConfiguration::from_toml(config)
+    pub fn init(config: &Table) -> Result<Services, Box<dyn std::error::Error + 'static>> {
+        let services: Vec<Arc<RwLock<Service>>> = ServiceConfiguration::from_toml(config)?
             .iter()
             .map(|s| Arc::new(RwLock::new(Service::from(s.clone()))))
             .collect();
+        NEXT_ID.store(services.len(), Ordering::SeqCst);
+
+        Ok(Arc::new(RwLock::new(services)))
+    }
+
+    /// Start a stopped service in a new thread.
+    ///
+    /// Returns `false` if the service isn't stopped.
+    pub fn start(s: &Arc<RwLock<Self>>) -> bool {
+        {
+            let mut service = s.write();
+            if service.state != ServiceState::Stopped || service.running {
+                return false;
+            }
+            service.stop_requested = false;
+            // Claim the service so it isn't started twice.
+            service.state = ServiceState::Restarting;
+        }
+
+        let s = s.clone();
+        thread::spawn(move || {
+            if let Err(e) = Service::spawn(s.clone()) {
+                let _span = logging::span(&s.read().configuration).entered();
+                error!("Stopped: {e}");
+                Self::emit(
+                    &s,
+                    EventKind::Errored {
+                        message: e.to_string(),
+                    },
+                );
+                s.write().running = false;
+                s.write().state = ServiceState::Stopped;
+                Self::emit(&s, EventKind::Stopped);
+            }
+        });
+
+        true
+    }
+
+    /// Stop a service, and keep it stopped until it's started again.
+    ///
+    /// Returns `false` if the service is already stopped.
+    pub fn stop(s: &Arc<RwLock<Self>>) -> bool {
+        if s.read().state == ServiceState::Stopped {
+            return false;
+        }
+
+        s.write().stop_requested = true;
+        if let Some(pid) = s.read().pid {
+            unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+        }
+
+        true
+    }
+
+    /// The service with the values of secret-looking environment variables and arguments (in both
+    /// `cmd` and `args`) hidden.
+    pub fn redacted(mut self) -> Self {
+        let conf = &mut self.configuration;
+        conf.envs = conf
+            .envs
+            .iter()
+            .map(|(key, value)| (key.clone(), SECRETS.value(key, value)))
+            .collect();
+        (conf.cmd, conf.args) = SECRETS.command(&conf.cmd, &conf.args);
+        self
+    }
+
+    /// Start managing a new service, and start it.
+    pub fn add(configuration: ServiceConfiguration) -> std::io::Result<Arc<RwLock<Self>>> {
+        logs::prepare(std::slice::from_ref(&configuration))?;
+
+        let s = Arc::new(RwLock::new(Service::from(configuration)));
+        SERVICES.write().push(s.clone());
+        caddy::reload();
+        Self::start(&s);
+        if s.read().configuration.watch {
+            watch::set(&s, true);
+        }
+
+        Ok(s)
+    }
+
+    /// Give a stopped service a new configuration, and start it again if `start` is set.
+    pub fn replace(
+        s: &Arc<RwLock<Self>>,
+        configuration: ServiceConfiguration,
+        start: bool,
+    ) -> std::io::Result<()> {
+        logs::prepare(std::slice::from_ref(&configuration))?;
+
+        {
+            let mut service = s.write();
+            if !watch::same_paths(&service.configuration, &configuration) {
+                // The running watcher stops once it notices; a new one watches the new paths.
+                service.watcher_started = false;
+            }
+            service.watching = configuration.watch;
+            service.configuration = configuration;
+            service.restarts = 0;
+            service.exit_code = None;
+            service.healthy = true;
+        }
+        caddy::reload();
+
+        if start {
+            Self::start(s);
+        }
+        let watching = s.read().watching;
+        watch::set(s, watching);
 
-        Ok(Arc::new(services))
+        Ok(())
+    }
+
+    /// Stop a service and stop managing it.
+    pub fn remove(s: &Arc<RwLock<Self>>) {
+        s.write().watching = false;
+        Self::stop(s);
+
+        SERVICES.write().retain(|service| !Arc::ptr_eq(service, s));
+        caddy::reload();
+    }
+
+    /// Send a signal to a service's process.
+    ///
+    /// Returns `false` if the service has no process to signal.
+    pub fn signal(s: &Arc<RwLock<Self>>, signal: i32) -> bool {
+        let Some(pid) = s.read().pid else {
+            return false;
+        };
+
+        unsafe { libc::kill(pid as i32, signal) == 0 }
+    }
+
+    /// Restart a running service right away, without counting it as a failure.
+    ///
+    /// Returns `false` if the service has no process to restart.
+    pub fn restart(s: &Arc<RwLock<Self>>) -> bool {
+        let Some(pid) = s.read().pid else {
+            return false;
+        };
+
+        s.write().restart_requested = true;
+        unsafe { libc::kill(pid as i32, libc::SIGTERM) };
+
+        true
+    }
+
+    fn emit(s: &Arc<RwLock<Self>>, kind: EventKind) {
+        let conf = s.read().configuration.clone();
+        events::emit(&conf, kind);
+    }
+
+    /// Emit a `ready` event once a newly started process is ready for connections.
+    fn announce_ready(s: &Arc<RwLock<Self>>, pid: u32) {
+        let (socket, port) = (s.read().configuration.socket, s.read().configuration.port);
+        let Some(port) = port.filter(|_| !socket) else {
+            Self::emit(s, EventKind::Ready { port });
+            return;
+        };
+
+        let s = s.clone();
+        let span = Span::current();
+        thread::spawn(move || {
+            let _span = span.entered();
+            if sockets::wait_until_listening(port, READY_TIMEOUT, || s.read().pid == Some(pid)) {
+                Self::emit(&s, EventKind::Ready { port: Some(port) });
+            } else if s.read().pid == Some(pid) {
+                warn!("Nothing is listening on port {port} yet");
+            }
+        });
+    }
+
+    /// Run the service's build command, logging its output to `<log_dir>/<name>.build.log`.
+    ///
+    /// Returns whether the build succeeded (or `true` if the service has no build command).
+    pub fn build(s: &Arc<RwLock<Self>>) -> std::io::Result<bool> {
+        let conf = s.read().configuration.clone();
+        let Some(build) = conf.build else {
+            return Ok(true);
+        };
+
+        let mut log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(logs::build_log_path(&conf.name))?;
+        writeln!(log, "$ {build}")?;
+
+        s.write().building = true;
+        let started = Instant::now();
+        let status = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&build)
+            .envs(conf.envs)
+            .current_dir(conf.wd)
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .status();
+        s.write().building = false;
+
+        let status = status?;
+        s.write().last_build = Some(Build {
+            success: status.success(),
+            exit_code: status.code(),
+            duration_ms: started.elapsed().as_millis(),
+        });
+
+        Ok(status.success())
+    }
+
+    /// Rebuild a service, restarting it with the new build if it's running.
+    ///
+    /// A failed build leaves the running instance alone.
+    pub fn rebuild(s: &Arc<RwLock<Self>>) -> std::io::Result<bool> {
+        let _span = logging::span(&s.read().configuration).entered();
+        info!("Rebuilding");
+
+        if !Self::build(s)? {
+            warn!("Build failed; keeping the running instance");
+            return Ok(false);
+        }
+
+        Self::restart(s);
+
+        Ok(true)
     }
 
     /// Spawn a service.
     ///
     /// Spawn a service with the given configuration. The service will be spawned in a new thread.
-    /// Funnel its stdout and stderr to a log file.
+    /// Funnel its stdout and stderr to a log file, rotated as configured in `[logs]`.
+    ///
+    /// If the service uses socket activation, its listening socket is bound once here and handed
+    /// to every restart, so it stays open while the process is down. On-demand services wait on
+    /// that socket for a connection before every start, and are stopped again once idle.
     ///
     pub fn spawn(s: Arc<RwLock<Self>>) -> std::io::Result<()> {
-        let fmt_service_name = cformat!(
-            "<blue, bold>{}</> (id <yellow>{}</>)",
-            s.read().configuration.name,
-            s.read().configuration.id
-        );
-        cprintln!("<green>Starting</> {fmt_service_name}");
+        let _span = logging::span(&s.read().configuration).entered();
+        info!("Starting");
 
         let mut attempts = 0;
         let mut command_successful = false;
 
-        let mut logfile_options = std::fs::OpenOptions::new();
-        let logfile_options = logfile_options.create(true).write(true).append(true);
+        if s.read().configuration.build.is_some() {
+            s.write().state = ServiceState::Building;
+            info!("Building");
+
+            if !Self::build(&s)? {
+                error!("Build failed; it will not be started");
+                Self::emit(
+                    &s,
+                    EventKind::Errored {
+                        message: "the build failed".to_owned(),
+                    },
+                );
+                s.write().state = ServiceState::Stopped;
+                Self::emit(&s, EventKind::Stopped);
+                return Ok(());
+            }
+        }
+
+        let (socket, port) = (s.read().configuration.socket, s.read().configuration.port);
+        let listener = match socket {
+            true => Some(sockets::bind(port.expect("a port to bind"))?),
+            false => None,
+        };
+
+        let name = s.read().configuration.name.clone();
+        let rules = Rules::new(&s);
+        let (stdout_sinks, stderr_sinks) =
+            Sink::open_all(&s.read().configuration, &s.read().output, rules.as_ref())?;
+        let mut instance = 0;
 
         while (!command_successful || s.read().configuration.restart_on_success)
             && RUNNING.load(Ordering::Relaxed)
+            && !s.read().stop_requested
         {
-            attempts += 1;
-            cprintln!("<green>Attempt #{}</>: {fmt_service_name}", attempts);
+            if s.read().configuration.on_demand {
+                s.write().state = ServiceState::Idle;
+                info!("Waiting for a connection");
 
-            let log = logfile_options.open(format!("logs/{}.log", s.read().configuration.name))?;
-            let log_err =
-                logfile_options.open(format!("logs/{}.error.log", s.read().configuration.name))?;
+                let listener = listener
+                    .as_ref()
+                    .expect("on-demand services to hold a socket");
+                if !sockets::wait_for_connection(listener, || s.read().stop_requested)? {
+                    break;
+                }
+            }
+
+            attempts += 1;
+            info!(attempt = attempts, "Starting process");
 
             let program = match PathBuf::from(&s.read().configuration.cmd).canonicalize() {
                 Ok(canonical_path) if canonical_path.is_file() => canonical_path
@@ -201,46 +664,171 @@ impl Service {
             };
 
             let mut program = program.split_whitespace();
+            let program_name = program.next().expect("a program name/path");
+            let program_args = program
+                .map(|s| s.to_string())
+                .chain(s.read().configuration.args.clone())
+                .collect::<Vec<String>>();
 
-            let mut command = Command::new(&program.next().expect("a program name/path"));
+            let mut command = match listener {
+                Some(ref listener) => sockets::activated_command(
+                    listener,
+                    &s.read().configuration.name,
+                    program_name,
+                    &program_args,
+                ),
+                None => {
+                    let mut command = Command::new(program_name);
+                    command.args(&program_args);
+                    command
+                }
+            };
             let command = command
-                .args(
-                    program
-                        .clone()
-                        .map(|s| s.to_string())
-                        .chain(s.read().configuration.args.clone())
-                        .collect::<Vec<String>>(),
-                )
                 .envs(s.read().configuration.envs.clone())
-                .current_dir("dashboard")
-                .stdout(log)
-                .stderr(log_err);
+                .current_dir(&s.read().configuration.wd)
+                .stdout(
+                    s.read()
+                        .configuration
+                        .stdout
+                        .stdio(!stdout_sinks.is_empty()),
+                )
+                .stderr(
+                    s.read()
+                        .configuration
+                        .stderr
+                        .stdio(!stderr_sinks.is_empty()),
+                );
 
             if let Some(port) = s.read().configuration.port {
                 command.env("PORT", port.to_string());
             }
 
+            let environment = crashes::environment(command);
+            let (started, started_instant) = (Utc::now(), Instant::now());
             let mut child = command.spawn()?;
+            instance += 1;
+            let mut captures = Vec::new();
+            if let Some(stdout) = child.stdout.take() {
+                captures.push(logs::capture(
+                    stdout,
+                    Stream::Stdout,
+                    &name,
+                    instance,
+                    stdout_sinks.clone(),
+                ));
+            }
+            if let Some(stderr) = child.stderr.take() {
+                captures.push(logs::capture(
+                    stderr,
+                    Stream::Stderr,
+                    &name,
+                    instance,
+                    stderr_sinks.clone(),
+                ));
+            }
             s.write().running = true;
+            s.write().healthy = true;
+            s.write().state = ServiceState::Running;
+            s.write().pid = Some(child.id());
+            Self::emit(
+                &s,
+                EventKind::Started {
+                    pid: child.id(),
+                    attempt: attempts,
+                },
+            );
+            Self::announce_ready(&s, child.id());
 
-            match child.wait() {
-                Ok(_) if !RUNNING.load(Ordering::Relaxed) => break,
-                Ok(output) if output.success() => {
-                    cprint!(
-                        "<yellow>Exit #{}</>: {fmt_service_name} successfully exited.",
-                        attempts
-                    );
+            // Don't hold the lock while waiting on the child
+            let (on_demand, port) = (
+                s.read().configuration.on_demand,
+                s.read().configuration.port,
+            );
+            let idle_timeout = Duration::from_secs(s.read().configuration.idle_timeout);
+
+            let exit = match (on_demand, port) {
+                (true, Some(port)) => sockets::wait_until_idle(&child, port, idle_timeout),
+                _ => crashes::wait(&child, true),
+            };
+            s.write().pid = None;
+            if let Ok(Some((status, _))) = exit {
+                s.write().exit_code = status.code();
+                Self::emit(
+                    &s,
+                    EventKind::Exited {
+                        exit_code: status.code(),
+                        signal: status.signal(),
+                    },
+                );
+            }
+
+            match exit {
+                Ok(_) if !RUNNING.load(Ordering::Relaxed) || s.read().stop_requested => break,
+                Ok(_) if s.read().restart_requested => {
+                    info!("Restarting");
+                    s.write().restart_requested = false;
+                    s.write().running = false;
+                    s.write().restarts += 1;
+                    attempts = 0;
+                    continue;
+                }
+                Ok(None) => {
+                    info!("Idle; stopped until the next connection");
+                    s.write().running = false;
+                    // It was doing fine, so the next start is a fresh one.
+                    attempts = 0;
+                    continue;
+                }
+                Ok(Some((status, _))) if status.success() => {
+                    info!(attempt = attempts, "Exited successfully");
                     command_successful = true;
                     s.write().running = false;
                 }
-                Ok(_) => {
-                    cprint!("<red>Failure #{}</>: {fmt_service_name}", attempts);
+                Ok(Some((status, resource_usage))) => {
                     s.write().running = false;
+
+                    // Give the last of its output a moment to be captured before reporting it.
+                    let deadline = Instant::now() + CAPTURE_GRACE;
+                    while captures.iter().any(|c| !c.is_finished()) && Instant::now() < deadline {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+
+                    let report = CrashReport::new(
+                        &s,
+                        instance,
+                        attempts,
+                        started,
+                        started_instant.elapsed(),
+                        status,
+                        resource_usage,
+                        environment,
+                    );
+                    match report.save() {
+                        Ok(_) => warn!(
+                            attempt = attempts,
+                            exit_code = report.exit_code,
+                            signal = report.signal,
+                            crash_report = %report.id,
+                            "Failed"
+                        ),
+                        Err(e) => {
+                            warn!(
+                                attempt = attempts,
+                                exit_code = report.exit_code,
+                                signal = report.signal,
+                                "Failed"
+                            );
+                            error!("Couldn't save a crash report: {e}");
+                        }
+                    }
                 }
-                Err(_) => {
-                    cprint!(
-                        "<red>Failure #{}</> <magenta>(couldn't even start)</>: {fmt_service_name}",
-                        attempts
+                Err(e) => {
+                    error!(attempt = attempts, "Failed (couldn't even start): {e}");
+                    Self::emit(
+                        &s,
+                        EventKind::Errored {
+                            message: e.to_string(),
+                        },
                     );
                     s.write().running = false;
                 }
@@ -249,7 +837,7 @@ impl Service {
             // Failures is attempts - 1 because the first attempt is not a failure.
             if let Some(max_restarts) = s.read().configuration.max_restarts {
                 if attempts > max_restarts {
-                    cprintln!(" | <cyan>It will not be restarted automatically.</>");
+                    warn!("It will not be restarted automatically");
                     break;
                 }
             }
@@ -260,19 +848,27 @@ impl Service {
                 1
             };
 
+            s.write().state = ServiceState::Restarting;
+            Self::emit(&s, EventKind::Restarting { delay_secs: delay });
             if delay > 0 {
-                cprintln!(" | <cyan>Restarting in {} seconds</>", delay);
-                std::thread::sleep(std::time::Duration::from_secs(delay));
+                info!("Restarting in {delay} seconds");
+                // Wait in steps, so a stop doesn't have to wait out the delay.
+                let restart_at = Instant::now() + Duration::from_secs(delay);
+                while Instant::now() < restart_at && !s.read().stop_requested {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
             } else {
-                cprintln!(" | <cyan>Restarting immediately</>");
+                info!("Restarting immediately");
             }
 
             s.write().restarts += 1;
         }
 
         s.write().running = false;
+        s.write().state = ServiceState::Stopped;
+        Self::emit(&s, EventKind::Stopped);
 
-        cprintln!("<magenta>Termination</>: {fmt_service_name}.");
+        info!("Terminated");
 
         Ok(())
     }
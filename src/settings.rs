@@ -0,0 +1,438 @@
+use crate::auth::Scope;
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::io::Read;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+use toml::Table;
+use toml_edit::{Document, Item};
+
+/// Read and parse the pmrs config file.
+pub fn load(path: &str) -> Result<Table, Box<dyn std::error::Error + 'static>> {
+    let mut config_file = File::open(path)?;
+    let mut config_file_buffer = Vec::new();
+    config_file.read_to_end(&mut config_file_buffer)?;
+
+    Ok(String::from_utf8_lossy(&config_file_buffer).parse()?)
+}
+
+lazy_static::lazy_static! {
+    /// Held while the config file is being rewritten.
+    static ref WRITING: Mutex<()> = Mutex::new(());
+}
+
+/// Write a service's definition to the config file under `[services.<name>]`, in place of
+/// `previous`'s if it's been renamed. The rest of the file, comments and all, is kept as it was.
+pub fn save_service(
+    path: &str,
+    name: &str,
+    definition: &Table,
+    previous: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    // Parse the definition as a document of its own, so it's formatted like one.
+    let mut table = toml::to_string(definition)?
+        .parse::<Document>()?
+        .as_table()
+        .clone();
+    table.set_implicit(false);
+
+    edit(path, |services| {
+        // Keep the service where it was in the file, or add it to the end.
+        let old = services.remove(previous.unwrap_or(name));
+        let position = old.as_ref().and_then(|old| old.as_table()?.position());
+        place(&mut table, position.unwrap_or(usize::MAX));
+        services.insert(name, Item::Table(table));
+    })
+}
+
+/// Put a table, and the tables in it, at a position in the file (after its parent, for the latter).
+fn place(table: &mut toml_edit::Table, position: usize) {
+    table.set_position(position);
+    for (_, item) in table.iter_mut() {
+        match item {
+            Item::Table(table) => place(table, position),
+            Item::ArrayOfTables(tables) => tables.iter_mut().for_each(|t| place(t, position)),
+            _ => {}
+        }
+    }
+}
+
+/// Remove a service's definition from the config file, keeping the rest of the file as it was.
+pub fn remove_service(path: &str, name: &str) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    edit(path, |services| {
+        services.remove(name);
+    })
+}
+
+fn edit(
+    path: &str,
+    f: impl FnOnce(&mut toml_edit::Table),
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let _writing = WRITING.lock();
+    let mut document: Document = fs::read_to_string(path)?.parse()?;
+
+    let services = document
+        .entry("services")
+        .or_insert(toml_edit::table())
+        .as_table_mut()
+        .ok_or("`services` in the config file isn't a table")?;
+    services.set_implicit(true);
+    f(services);
+
+    fs::write(path, document.to_string())?;
+    Ok(())
+}
+
+/// Global (non-service) settings from the top level of the config file.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub port_range: RangeInclusive<u16>, // The range `port = "auto"` services are given a port from.
+    pub log_dir: PathBuf, // Where service (and pmrs) logs are written, unless configured otherwise.
+    pub log_level: String, // The least severe of pmrs' own messages to log, or a filter.
+    pub log_format: DaemonLogFormat, // How pmrs' own messages are logged.
+    pub logs: LogSettings,
+    pub crashes: CrashSettings,
+    pub auth: AuthSettings,
+    pub system: SystemSettings,
+    pub redact: Vec<String>, // Globs of environment variable and option names whose values are hidden.
+}
+impl Settings {
+    pub fn from_toml(config: &Table) -> Self {
+        Self {
+            port_range: config
+                .get("port_range")
+                .map(|i| {
+                    let range = i
+                        .as_array()
+                        .expect("the port range to be an array of two ports")
+                        .iter()
+                        .map(|p| p.as_integer().expect("a port number") as u16)
+                        .collect::<Vec<u16>>();
+                    match range[..] {
+                        [start, end] if start <= end => start..=end,
+                        _ => panic!("the port range should be [start, end]"),
+                    }
+                })
+                .unwrap_or(4000..=4999),
+            log_dir: config
+                .get("log_dir")
+                .map(|i| PathBuf::from(i.as_str().expect("a str")))
+                .unwrap_or(PathBuf::from(*crate::DEFAULT_LOG_DIR)),
+            log_level: config
+                .get("log_level")
+                .map(|i| i.as_str().expect("a str").to_owned())
+                .unwrap_or("info".to_owned()),
+            log_format: config
+                .get("log_format")
+                .map(|i| match i.as_str().expect("a str") {
+                    "text" => DaemonLogFormat::Text,
+                    "json" => DaemonLogFormat::Json,
+                    other => panic!("unknown log format {other}"),
+                })
+                .unwrap_or(DaemonLogFormat::Text),
+            logs: config
+                .get("logs")
+                .map(|i| LogSettings::from_toml(i.as_table().expect("a table")))
+                .unwrap_or_default(),
+            crashes: config
+                .get("crashes")
+                .map(|i| CrashSettings::from_toml(i.as_table().expect("a table")))
+                .unwrap_or_default(),
+            auth: config
+                .get("auth")
+                .map(|i| AuthSettings::from_toml(i.as_table().expect("a table")))
+                .unwrap_or_default(),
+            system: config
+                .get("system")
+                .map(|i| SystemSettings::from_toml(i.as_table().expect("a table")))
+                .unwrap_or_default(),
+            redact: config
+                .get("redact")
+                .map(|i| {
+                    i.as_array()
+                        .expect("an array")
+                        .iter()
+                        .map(|p| p.as_str().expect("a str").to_owned())
+                        .collect()
+                })
+                .unwrap_or(
+                    [
+                        "*KEY*",
+                        "*SECRET*",
+                        "*TOKEN*",
+                        "*PASSWORD*",
+                        "*PASSWD*",
+                        "*CREDENTIAL*",
+                        "*AUTH*",
+                    ]
+                    .map(|p| p.to_owned())
+                    .to_vec(),
+                ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaemonLogFormat {
+    Text, // Human readable, coloured on a terminal.
+    Json, // One JSON object per message, for journald and log shippers.
+}
+
+/// How service logs are written and rotated, from the `[logs]` table.
+#[derive(Debug, Clone)]
+pub struct LogSettings {
+    pub max_size: Option<u64>, // Rotate a log once it would grow past this many bytes.
+    pub rotate_every: Option<Duration>, // Rotate a log once it's this old, even across restarts.
+    pub retain: usize,         // How many rotated logs to keep per log file.
+    pub compress: bool,        // Whether to gzip rotated logs.
+    pub format: LogFormat,     // How captured lines are written.
+    pub merge: bool,           // Whether to write stdout and stderr to the same file.
+    pub forward_buffer: usize, // How many lines to hold per forward target before dropping them.
+    pub buffer_lines: usize,   // How many recent lines of each service's output to keep in memory.
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Plain, // `<time> <stream> [<service>#<instance>] <line>`
+    Json,  // One JSON object per line (JSON Lines)
+}
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            rotate_every: None,
+            retain: 5,
+            compress: false,
+            format: LogFormat::Plain,
+            merge: false,
+            forward_buffer: 1000,
+            buffer_lines: 1000,
+        }
+    }
+}
+impl LogSettings {
+    pub fn from_toml(config: &Table) -> Self {
+        let default = Self::default();
+
+        Self {
+            max_size: config.get("max_size").map(|i| match i {
+                toml::Value::Integer(bytes) => *bytes as u64,
+                _ => parse_size(i.as_str().expect("a size")).expect("a size like \"10M\""),
+            }),
+            rotate_every: config.get("rotate_every").map(|i| match i {
+                toml::Value::Integer(seconds) => Duration::from_secs(*seconds as u64),
+                _ => match i.as_str().expect("a period") {
+                    "hourly" => Duration::from_secs(60 * 60),
+                    "daily" => Duration::from_secs(24 * 60 * 60),
+                    "weekly" => Duration::from_secs(7 * 24 * 60 * 60),
+                    other => panic!("unknown rotation period {other}"),
+                },
+            }),
+            retain: config
+                .get("retain")
+                .map(|i| i.as_integer().expect("a number of files") as usize)
+                .unwrap_or(default.retain),
+            compress: config
+                .get("compress")
+                .map(|i| i.as_bool().expect("a bool"))
+                .unwrap_or(default.compress),
+            format: config
+                .get("format")
+                .map(|i| match i.as_str().expect("a str") {
+                    "plain" => LogFormat::Plain,
+                    "json" => LogFormat::Json,
+                    other => panic!("unknown log format {other}"),
+                })
+                .unwrap_or(default.format),
+            merge: config
+                .get("merge")
+                .map(|i| i.as_bool().expect("a bool"))
+                .unwrap_or(default.merge),
+            forward_buffer: config
+                .get("forward_buffer")
+                .map(|i| i.as_integer().expect("a number of lines") as usize)
+                .unwrap_or(default.forward_buffer),
+            buffer_lines: config
+                .get("buffer_lines")
+                .map(|i| i.as_integer().expect("a number of lines") as usize)
+                .unwrap_or(default.buffer_lines),
+        }
+    }
+}
+
+/// What goes into crash reports, from the `[crashes]` table.
+#[derive(Debug, Clone)]
+pub struct CrashSettings {
+    pub lines: usize,  // How many of the last lines of output to include.
+    pub retain: usize, // How many reports to keep per service.
+}
+impl Default for CrashSettings {
+    fn default() -> Self {
+        Self {
+            lines: 50,
+            retain: 20,
+        }
+    }
+}
+impl CrashSettings {
+    pub fn from_toml(config: &Table) -> Self {
+        let default = Self::default();
+
+        Self {
+            lines: config
+                .get("lines")
+                .map(|i| i.as_integer().expect("a number of lines") as usize)
+                .unwrap_or(default.lines),
+            retain: config
+                .get("retain")
+                .map(|i| i.as_integer().expect("a number of reports") as usize)
+                .unwrap_or(default.retain),
+        }
+    }
+}
+
+/// A setting from a table, or an error saying what it should be if it's there but isn't that.
+pub fn setting<'a, T>(
+    table: &'a Table,
+    key: &str,
+    expected: &str,
+    convert: impl FnOnce(&'a toml::Value) -> Option<T>,
+) -> Result<Option<T>, String> {
+    table
+        .get(key)
+        .map(|value| convert(value).ok_or_else(|| format!("{key} should be {expected}")))
+        .transpose()
+}
+
+/// An array of strings, for `setting`.
+pub fn strings(value: &toml::Value) -> Option<Vec<String>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|i| i.as_str().map(str::to_owned))
+        .collect()
+}
+
+/// A whole number that isn't negative, for `setting`.
+pub fn count(value: &toml::Value) -> Option<u64> {
+    u64::try_from(value.as_integer()?).ok()
+}
+
+/// Parse a size like `512`, `64K`, `10M` or `1G` into bytes.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, multiplier) = match size.char_indices().last()? {
+        (i, 'K' | 'k') => (&size[..i], 1 << 10),
+        (i, 'M' | 'm') => (&size[..i], 1 << 20),
+        (i, 'G' | 'g') => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+
+    number.trim().parse::<u64>().ok().map(|n| n * multiplier)
+}
+
+/// What `/system` shows of the host's processes, from the `[system]` table.
+#[derive(Debug, Clone)]
+pub struct SystemSettings {
+    pub environ: bool,             // Whether to show processes' environments at all.
+    pub processes: Processes,      // Which processes to list.
+    pub sample_interval: Duration, // How often the host's metrics are sampled.
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Processes {
+    All,     // Every process on the host.
+    Managed, // Only services' processes, and the processes they started.
+}
+impl Default for SystemSettings {
+    fn default() -> Self {
+        Self {
+            environ: true,
+            processes: Processes::All,
+            sample_interval: Duration::from_secs(2),
+        }
+    }
+}
+impl SystemSettings {
+    pub fn from_toml(config: &Table) -> Self {
+        let default = Self::default();
+
+        Self {
+            environ: config
+                .get("environ")
+                .map(|i| i.as_bool().expect("a bool"))
+                .unwrap_or(default.environ),
+            processes: config
+                .get("processes")
+                .map(|i| match i.as_str().expect("a str") {
+                    "all" => Processes::All,
+                    "managed" => Processes::Managed,
+                    other => panic!("unknown process listing {other}"),
+                })
+                .unwrap_or(default.processes),
+            sample_interval: config
+                .get("sample_interval")
+                .map(|i| {
+                    Duration::from_millis(i.as_integer().expect("a number of milliseconds") as u64)
+                })
+                .unwrap_or(default.sample_interval),
+        }
+    }
+}
+
+/// Who may use the API, from the `[auth]` table. With no tokens or users, anyone can.
+#[derive(Debug, Clone, Default)]
+pub struct AuthSettings {
+    pub tokens: Vec<Credential>, // Tokens sent as `Authorization: Bearer <token>`.
+    pub users: Vec<Credential>,  // Users who log in with basic auth.
+}
+
+/// A token, or a user's name and password, and what it may do.
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub name: String, // The user's name, or the token's (if it's given one).
+    pub secret: String,
+    pub scope: Scope,
+}
+impl AuthSettings {
+    pub fn from_toml(config: &Table) -> Self {
+        let credentials = |key: &str, name: &str, secret: &str| -> Vec<Credential> {
+            config
+                .get(key)
+                .map(|i| i.as_array().expect("an array"))
+                .unwrap_or(&vec![])
+                .iter()
+                .map(|i| {
+                    let i = i.as_table().expect("a table");
+                    let get = |key: &str| i.get(key).map(|v| v.as_str().expect("a str"));
+
+                    Credential {
+                        name: get(name).unwrap_or_default().to_owned(),
+                        secret: get(secret).expect("a token or password").to_owned(),
+                        scope: Scope::parse(get("scope").expect("a scope"))
+                            .expect("a scope of read, control or admin"),
+                    }
+                })
+                .collect()
+        };
+
+        Self {
+            tokens: credentials("tokens", "name", "token"),
+            users: credentials("users", "username", "password"),
+        }
+    }
+
+    /// The token with the most scope, for pmrs' own clients.
+    pub fn strongest_token(&self) -> Option<&str> {
+        self.tokens
+            .iter()
+            .max_by_key(|t| t.scope)
+            .map(|t| t.secret.as_str())
+    }
+
+    /// Whether the API requires credentials.
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.users.is_empty()
+    }
+}
@@ -0,0 +1,160 @@
+use crate::crashes::{self, ResourceUsage};
+use crate::RUNNING;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use std::{fs, io, thread};
+
+/// The first file descriptor passed to a socket-activated process (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: i32 = 3;
+/// `TCP_ESTABLISHED`, as written in the state column of `/proc/net/tcp`.
+const TCP_ESTABLISHED: &str = "01";
+/// `TCP_LISTEN`, likewise.
+const TCP_LISTEN: &str = "0A";
+
+/// Bind the listening socket pmrs holds on behalf of a service.
+///
+/// The socket outlives the service's process, so connections made while it restarts queue up in
+/// the backlog instead of being refused.
+pub fn bind(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind(("0.0.0.0", port))
+}
+
+/// Build a command that receives `listener` as fd 3, following the `LISTEN_FDS`/`LISTEN_PID`
+/// convention used by systemd socket activation.
+///
+/// `LISTEN_PID` has to be the pid of the process that ends up running `program`, which isn't
+/// known before forking, so the program is exec'd through `sh` and the shell fills in its own pid.
+pub fn activated_command(
+    listener: &TcpListener,
+    name: &str,
+    program: &str,
+    args: &[String],
+) -> Command {
+    let fd = listener.as_raw_fd();
+
+    let mut command = Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(r#"LISTEN_PID=$$ exec "$0" "$@""#)
+        .arg(program)
+        .args(args)
+        .env("LISTEN_FDS", "1")
+        .env("LISTEN_FDNAMES", name);
+
+    // Safety: only async-signal-safe calls (dup2/fcntl) are made between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            if fd == LISTEN_FDS_START {
+                // Already in place; just make sure it survives the exec.
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            } else if libc::dup2(fd, LISTEN_FDS_START) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+
+    command
+}
+
+/// Block until a client connects to `listener`, without accepting the connection.
+///
+/// Returns `false` if pmrs started shutting down, or the service was stopped, first.
+pub fn wait_for_connection(listener: &TcpListener, stopped: impl Fn() -> bool) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    while RUNNING.load(Ordering::Relaxed) && !stopped() {
+        // Wake up every second to notice a shutdown.
+        match unsafe { libc::poll(&mut pollfd, 1, 1_000) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            0 => continue,
+            _ => return Ok(true),
+        }
+    }
+
+    Ok(false)
+}
+
+/// The number of established (or queued, not yet accepted) TCP connections to a local port.
+pub fn connection_count(port: u16) -> usize {
+    count_sockets(port, TCP_ESTABLISHED)
+}
+
+/// Wait for something to listen on a local port, for up to `timeout` or until `alive` says
+/// whatever was going to listen has gone. Returns whether something did.
+pub fn wait_until_listening(port: u16, timeout: Duration, alive: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline && alive() {
+        if count_sockets(port, TCP_LISTEN) > 0 {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    false
+}
+
+/// The number of TCP sockets on a local port in a state, from the kernel's socket tables.
+fn count_sockets(port: u16, wanted: &str) -> usize {
+    ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|table| fs::read_to_string(table).ok())
+        .map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter(|line| {
+                    let mut columns = line.split_whitespace().skip(1);
+                    let local_port = columns
+                        .next()
+                        .and_then(|address| address.rsplit(':').next())
+                        .and_then(|p| u16::from_str_radix(p, 16).ok());
+                    let state = columns.nth(1);
+
+                    local_port == Some(port) && state == Some(wanted)
+                })
+                .count()
+        })
+        .sum()
+}
+
+/// Wait for an on-demand service's process to exit, stopping it once nobody has been connected
+/// to `port` for `idle_timeout`.
+///
+/// Returns `None` if the process was stopped for being idle.
+pub fn wait_until_idle(
+    child: &Child,
+    port: u16,
+    idle_timeout: Duration,
+) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    let mut last_active = Instant::now();
+
+    loop {
+        if let Some(exit) = crashes::wait(child, false)? {
+            return Ok(Some(exit));
+        }
+
+        if connection_count(port) > 0 {
+            last_active = Instant::now();
+        } else if last_active.elapsed() >= idle_timeout {
+            unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+            crashes::wait(child, true)?;
+            return Ok(None);
+        }
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,214 @@
+use crate::auth::Scope;
+use crate::logs::OutputBuffer;
+use crate::services::Service;
+use crate::{events, metrics, web, SETTINGS};
+use rocket::tokio::sync::{broadcast, mpsc};
+use rocket::tokio::task::JoinHandle;
+use rocket::tokio::time;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often subscribers are told about changes to services, unless they ask for another rate.
+const SERVICES_INTERVAL: Duration = Duration::from_millis(500);
+/// The fastest rate updates can be asked for.
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Something a websocket can subscribe to.
+#[derive(Debug, Clone)]
+pub enum Topic {
+    Services,                // Every service's state, whenever it changes.
+    System,                  // The host's metrics, whenever they're sampled.
+    Logs(Arc<OutputBuffer>), // A service's output, line by line.
+    Events,                  // Events, as they're emitted.
+}
+impl Topic {
+    /// Parse a topic: `services`, `system`, `events` or `logs:<service name or id>`.
+    pub fn parse(topic: &str) -> Result<Self, String> {
+        match topic.split_once(':') {
+            None if topic == "services" => Ok(Topic::Services),
+            None if topic == "system" => Ok(Topic::System),
+            None if topic == "events" => Ok(Topic::Events),
+            Some(("logs", service)) => crate::services::all()
+                .iter()
+                .map(|s| s.read())
+                .find(|s| {
+                    s.configuration.name == service || s.configuration.id.to_string() == service
+                })
+                .map(|s| Topic::Logs(s.output.clone()))
+                .ok_or_else(|| format!("no service {service}")),
+            _ => Err(format!("unknown topic {topic}")),
+        }
+    }
+
+    /// The scope needed to subscribe.
+    pub fn scope(&self) -> Scope {
+        match self {
+            Topic::System => Scope::Admin,
+            _ => Scope::Read,
+        }
+    }
+}
+
+/// A message from a subscriber, like `{"subscribe": "logs:web"}`, `{"subscribe": "system",
+/// "interval": 1000}` or `{"unsubscribe": "events"}`.
+#[derive(Debug)]
+pub enum Request {
+    Subscribe {
+        topic: String,
+        interval: Option<u64>, // Milliseconds between checks for changes, for services and system.
+    },
+    Unsubscribe {
+        topic: String,
+    },
+}
+impl Request {
+    pub fn parse(message: &str) -> Option<Self> {
+        let message: Value = serde_json::from_str(message).ok()?;
+        let interval = message.get("interval").and_then(|i| i.as_u64());
+        if let Some(topic) = message.get("subscribe").and_then(|t| t.as_str()) {
+            return Some(Request::Subscribe {
+                topic: topic.to_owned(),
+                interval,
+            });
+        }
+        let topic = message.get("unsubscribe")?.as_str()?;
+        Some(Request::Unsubscribe {
+            topic: topic.to_owned(),
+        })
+    }
+}
+
+/// A websocket's subscriptions, each feeding its updates into one channel the socket sends from.
+pub struct Subscriptions {
+    scope: Scope,
+    sender: mpsc::Sender<String>,
+    feeds: HashMap<String, JoinHandle<()>>,
+}
+impl Subscriptions {
+    /// Subscriptions for a subscriber with a scope, and where their updates are received.
+    pub fn new(scope: Scope) -> (Self, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::channel(SETTINGS.logs.buffer_lines.max(1));
+        let subscriptions = Self {
+            scope,
+            sender,
+            feeds: HashMap::new(),
+        };
+
+        (subscriptions, receiver)
+    }
+
+    /// Handle a request, returning the reply to send.
+    pub fn handle(&mut self, request: Request) -> Value {
+        match request {
+            Request::Subscribe { topic, interval } => {
+                let parsed = match Topic::parse(&topic) {
+                    Ok(parsed) => parsed,
+                    Err(e) => return json!({ "error": e }),
+                };
+                if parsed.scope() > self.scope {
+                    return json!({ "error": format!("{topic} needs more scope") });
+                }
+
+                let interval = interval.map(|i| Duration::from_millis(i).max(MIN_INTERVAL));
+                let feed = rocket::tokio::spawn(feed(
+                    topic.clone(),
+                    parsed,
+                    self.scope,
+                    interval,
+                    self.sender.clone(),
+                ));
+                if let Some(previous) = self.feeds.insert(topic.clone(), feed) {
+                    previous.abort();
+                }
+
+                json!({ "subscribed": topic })
+            }
+            Request::Unsubscribe { topic } => match self.feeds.remove(&topic) {
+                Some(feed) => {
+                    feed.abort();
+                    json!({ "unsubscribed": topic })
+                }
+                None => json!({ "error": format!("not subscribed to {topic}") }),
+            },
+        }
+    }
+}
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for feed in self.feeds.values() {
+            feed.abort();
+        }
+    }
+}
+
+fn update(topic: &str, data: impl serde::Serialize) -> String {
+    json!({ "topic": topic, "data": data }).to_string()
+}
+
+/// Send a topic's updates until the subscriber goes away.
+async fn feed(
+    name: String,
+    topic: Topic,
+    scope: Scope,
+    interval: Option<Duration>,
+    sender: mpsc::Sender<String>,
+) {
+    match topic {
+        Topic::Services => {
+            let mut ticks = time::interval(interval.unwrap_or(SERVICES_INTERVAL));
+            let mut last = None;
+            loop {
+                ticks.tick().await;
+                let services: Vec<Service> = crate::services::all()
+                    .iter()
+                    .map(|s| web::shown(s, scope))
+                    .collect();
+                let services = json!(services);
+                if last.as_ref() != Some(&services) {
+                    if sender.send(update(&name, &services)).await.is_err() {
+                        return;
+                    }
+                    last = Some(services);
+                }
+            }
+        }
+        Topic::System => {
+            let sample_interval = SETTINGS.system.sample_interval.max(MIN_INTERVAL);
+            let mut ticks = time::interval(interval.unwrap_or(sample_interval));
+            let mut last = None;
+            loop {
+                ticks.tick().await;
+                let system = metrics::latest();
+                if last.as_ref().is_some_and(|last| Arc::ptr_eq(last, &system)) {
+                    continue;
+                }
+                if sender.send(update(&name, &system)).await.is_err() {
+                    return;
+                }
+                last = Some(system);
+            }
+        }
+        Topic::Logs(output) => forward(&name, output.subscribe(), &sender).await,
+        Topic::Events => forward(&name, events::subscribe(), &sender).await,
+    }
+}
+
+async fn forward<T: Clone + serde::Serialize>(
+    name: &str,
+    mut receiver: broadcast::Receiver<T>,
+    sender: &mpsc::Sender<String>,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(item) => {
+                if sender.send(update(name, item)).await.is_err() {
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
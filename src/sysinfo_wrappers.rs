@@ -1,42 +1,74 @@
-use parking_lot::RwLock;
-use serde::Serialize;
-use std::collections::HashMap;
-use sysinfo::{CpuExt, DiskExt, NetworksExt, ProcessExt, UserExt};
+use crate::redact::SECRETS;
+use crate::settings::Processes;
+use crate::SETTINGS;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use sysinfo::{CpuExt, DiskExt, NetworksExt, Pid, ProcessExt, UserExt};
 use sysinfo::{PidExt, SystemExt};
 
+/// Whether a process is one of the services', or was started by one.
+fn is_managed(
+    pid: Pid,
+    processes: &HashMap<Pid, sysinfo::Process>,
+    services: &HashSet<u32>,
+) -> bool {
+    iter::successors(Some(pid), |pid| processes.get(pid)?.parent())
+        .take(processes.len())
+        .any(|pid| services.contains(&pid.as_u32()))
+}
+
 #[derive(Serialize, Clone)]
 pub struct System {
-    process_list: HashMap<u32, Process>,
-    mem_total: u64,
-    mem_free: u64,
-    mem_used: u64,
-    mem_available: u64,
-    swap_total: u64,
-    swap_free: u64,
-    swap_used: u64,
-    global_cpu: Cpu,
-    cpus: Vec<Cpu>,
-    physical_core_count: Option<usize>,
-    disks: Vec<Disk>,
-    interfaces: Vec<String>,
-    users: Vec<User>,
-    boot_time: u64,
-    name: Option<String>,
-    long_os_version: Option<String>,
-    host_name: Option<String>,
-    kernel_version: Option<String>,
-    os_version: Option<String>,
-    distribution_id: String,
+    pub sampled_at: DateTime<Utc>,
+    pub process_list: HashMap<u32, Process>,
+    pub mem_total: u64,
+    pub mem_free: u64,
+    pub mem_used: u64,
+    pub mem_available: u64,
+    pub swap_total: u64,
+    pub swap_free: u64,
+    pub swap_used: u64,
+    pub global_cpu: Cpu,
+    pub cpus: Vec<Cpu>,
+    pub physical_core_count: Option<usize>,
+    pub disks: Vec<Disk>,
+    pub interfaces: Vec<String>,
+    pub users: Vec<User>,
+    pub boot_time: u64,
+    pub name: Option<String>,
+    pub long_os_version: Option<String>,
+    pub host_name: Option<String>,
+    pub kernel_version: Option<String>,
+    pub os_version: Option<String>,
+    pub distribution_id: String,
 }
 impl System {
-    pub fn init(info: &RwLock<sysinfo::System>) -> Self {
-        let value = info.read();
+    pub fn init(value: &sysinfo::System) -> Self {
+        let processes = value.processes();
+        let services: HashSet<u32> = crate::services::all()
+            .iter()
+            .filter_map(|s| s.read().pid)
+            .collect();
 
         Self {
-            process_list: value
-                .processes()
+            sampled_at: Utc::now(),
+            process_list: processes
                 .iter()
-                .map(|(pid, proc)| (pid.as_u32(), proc.into()))
+                .filter(|(pid, _)| match SETTINGS.system.processes {
+                    Processes::All => true,
+                    Processes::Managed => is_managed(**pid, processes, &services),
+                })
+                .map(|(pid, proc)| {
+                    let mut process: Process = proc.into();
+                    process.user = proc
+                        .user_id()
+                        .and_then(|uid| value.get_user_by_id(uid))
+                        .map(|user| user.name().to_owned());
+                    (pid.as_u32(), process)
+                })
                 .collect(),
             mem_total: value.total_memory(),
             mem_free: value.free_memory(),
@@ -64,6 +96,239 @@ impl System {
             distribution_id: value.distribution_id(),
         }
     }
+
+    /// A process and every process it started (and so on), if it's still running.
+    pub fn process_tree(&self, pid: u32) -> Vec<&Process> {
+        let mut tree = Vec::new();
+        let mut pids = vec![pid];
+        while let Some(pid) = pids.pop() {
+            let Some(process) = self.process_list.get(&pid) else {
+                continue;
+            };
+            tree.push(process);
+            pids.extend(
+                self.process_list
+                    .iter()
+                    .filter(|(_, child)| child.parent == Some(pid as i32))
+                    .map(|(child, _)| *child),
+            );
+        }
+
+        tree
+    }
+
+    /// The parts of the system a selection asks for.
+    pub fn select<'a>(&'a self, selection: &'a Selection) -> SystemView<'a> {
+        let wants =
+            |section| selection.sections.is_empty() || selection.sections.contains(&section);
+        let process_list = wants(Section::Processes).then(|| self.select_processes(selection));
+
+        SystemView {
+            sampled_at: self.sampled_at,
+            host: wants(Section::Host).then_some(HostView {
+                interfaces: &self.interfaces,
+                users: &self.users,
+                boot_time: self.boot_time,
+                name: &self.name,
+                long_os_version: &self.long_os_version,
+                host_name: &self.host_name,
+                kernel_version: &self.kernel_version,
+                os_version: &self.os_version,
+                distribution_id: &self.distribution_id,
+            }),
+            memory: wants(Section::Memory).then_some(MemoryView {
+                mem_total: self.mem_total,
+                mem_free: self.mem_free,
+                mem_used: self.mem_used,
+                mem_available: self.mem_available,
+                swap_total: self.swap_total,
+                swap_free: self.swap_free,
+                swap_used: self.swap_used,
+            }),
+            cpus: wants(Section::Cpus).then_some(CpusView {
+                global_cpu: &self.global_cpu,
+                cpus: &self.cpus,
+                physical_core_count: self.physical_core_count,
+            }),
+            disks: wants(Section::Disks).then_some(&self.disks),
+            process_count: process_list.as_ref().map(|p| p.total),
+            process_list,
+        }
+    }
+
+    fn select_processes<'a>(&'a self, selection: &'a Selection) -> ProcessesView<'a> {
+        let name = selection.name.as_ref().map(|name| name.to_lowercase());
+        let mut processes: Vec<(u32, &Process)> = self
+            .process_list
+            .iter()
+            .filter(|(pid, _)| selection.pids.is_empty() || selection.pids.contains(pid))
+            .filter(|(_, p)| {
+                name.as_ref()
+                    .is_none_or(|name| p.name.to_lowercase().contains(name))
+            })
+            .filter(|(_, p)| selection.user.is_none() || p.user.as_ref() == selection.user.as_ref())
+            .map(|(pid, p)| (*pid, p))
+            .collect();
+
+        match selection.sort {
+            Some(SortBy::Cpu) => {
+                processes.sort_by(|(_, a), (_, b)| b.cpu_usage.total_cmp(&a.cpu_usage))
+            }
+            Some(SortBy::Memory) => processes.sort_by_key(|(_, p)| Reverse(p.memory)),
+            None => processes.sort_by_key(|(pid, _)| *pid),
+        }
+        let total = processes.len();
+        processes.drain(..selection.offset.min(total));
+        if let Some(limit) = selection.limit {
+            processes.truncate(limit);
+        }
+
+        ProcessesView {
+            processes,
+            total,
+            exclude: &selection.exclude,
+        }
+    }
+}
+
+/// A part of the system that can be asked for on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Host,      // Its name, OS, boot time, users and network interfaces.
+    Memory,    // Memory and swap.
+    Cpus,      // Overall and per CPU usage.
+    Disks,     // Mounted disks.
+    Processes, // The process list.
+}
+impl Section {
+    pub fn parse(section: &str) -> Option<Self> {
+        match section {
+            "host" => Some(Section::Host),
+            "memory" => Some(Section::Memory),
+            "cpus" => Some(Section::Cpus),
+            "disks" => Some(Section::Disks),
+            "processes" => Some(Section::Processes),
+            _ => None,
+        }
+    }
+}
+
+/// What to sort processes by, highest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    Cpu,
+    Memory,
+}
+impl SortBy {
+    pub fn parse(sort: &str) -> Option<Self> {
+        match sort {
+            "cpu" => Some(SortBy::Cpu),
+            "memory" => Some(SortBy::Memory),
+            _ => None,
+        }
+    }
+}
+
+/// Which parts of the system to show, and which of its processes.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub sections: Vec<Section>, // Every section, if empty.
+    pub name: Option<String>,   // Only processes whose names contain this, ignoring case.
+    pub user: Option<String>,   // Only processes run by this user.
+    pub pids: Vec<u32>,         // Only these processes, unless empty.
+    pub sort: Option<SortBy>,   // By PID, if not given.
+    pub offset: usize,          // Skip this many processes first.
+    pub limit: Option<usize>,   // At most this many processes.
+    pub exclude: Vec<String>,   // Fields to leave out of each process.
+}
+
+/// Process fields that can be left out.
+pub const PROCESS_FIELDS: [&str; 14] = [
+    "name",
+    "cmd",
+    "exe",
+    "parent",
+    "user",
+    "environ",
+    "cwd",
+    "root",
+    "memory",
+    "virtual_memory",
+    "start_time",
+    "run_time",
+    "cpu_usage",
+    "disk_usage",
+];
+
+/// The sections of `System` a selection asked for, serialized the same way.
+#[derive(Serialize)]
+pub struct SystemView<'a> {
+    sampled_at: DateTime<Utc>,
+    #[serde(flatten)]
+    host: Option<HostView<'a>>,
+    #[serde(flatten)]
+    memory: Option<MemoryView>,
+    #[serde(flatten)]
+    cpus: Option<CpusView<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disks: Option<&'a Vec<Disk>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    process_list: Option<ProcessesView<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    process_count: Option<usize>, // How many processes matched, before `offset` and `limit`.
+}
+
+#[derive(Serialize)]
+struct HostView<'a> {
+    interfaces: &'a Vec<String>,
+    users: &'a Vec<User>,
+    boot_time: u64,
+    name: &'a Option<String>,
+    long_os_version: &'a Option<String>,
+    host_name: &'a Option<String>,
+    kernel_version: &'a Option<String>,
+    os_version: &'a Option<String>,
+    distribution_id: &'a String,
+}
+
+#[derive(Serialize)]
+struct MemoryView {
+    mem_total: u64,
+    mem_free: u64,
+    mem_used: u64,
+    mem_available: u64,
+    swap_total: u64,
+    swap_free: u64,
+    swap_used: u64,
+}
+
+#[derive(Serialize)]
+struct CpusView<'a> {
+    global_cpu: &'a Cpu,
+    cpus: &'a Vec<Cpu>,
+    physical_core_count: Option<usize>,
+}
+
+/// Selected processes by PID, in the order they were sorted into.
+struct ProcessesView<'a> {
+    processes: Vec<(u32, &'a Process)>,
+    total: usize, // Before `offset` and `limit`.
+    exclude: &'a [String],
+}
+impl Serialize for ProcessesView<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.exclude.is_empty() {
+            return serializer.collect_map(self.processes.iter().copied());
+        }
+
+        serializer.collect_map(self.processes.iter().map(|(pid, process)| {
+            let mut process = serde_json::to_value(process).unwrap_or_default();
+            if let Some(fields) = process.as_object_mut() {
+                fields.retain(|field, _| !self.exclude.contains(field));
+            }
+            (*pid, process)
+        }))
+    }
 }
 
 #[derive(Serialize, Clone)]
@@ -72,6 +337,7 @@ pub struct Process {
     pub cmd: Vec<String>,
     pub exe: String,
     pub parent: Option<i32>,
+    pub user: Option<String>,
     pub environ: Vec<String>,
     pub cwd: String,
     pub root: String,
@@ -86,10 +352,14 @@ impl From<&sysinfo::Process> for Process {
     fn from(value: &sysinfo::Process) -> Self {
         Self {
             name: value.name().to_owned(),
-            cmd: value.cmd().to_owned(),
+            cmd: SECRETS.cmd(value.cmd()),
             exe: value.exe().to_string_lossy().to_string(),
             parent: value.parent().map(|pid| pid.as_u32() as i32),
-            environ: value.environ().to_owned(),
+            user: None, // Set by `System::init`, which can look users up.
+            environ: match SETTINGS.system.environ {
+                true => SECRETS.environ(value.environ()),
+                false => Vec::new(),
+            },
             cwd: value.cwd().to_string_lossy().to_string(),
             root: value.root().to_string_lossy().to_string(),
             memory: value.memory(),
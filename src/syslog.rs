@@ -0,0 +1,234 @@
+use crate::logs::{LogLine, Stream};
+use crate::SETTINGS;
+use chrono::SecondsFormat;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+/// The local syslog socket.
+const SYSLOG_SOCKET: &str = "/dev/log";
+const SEVERITY_ERROR: u8 = 3;
+const SEVERITY_INFO: u8 = 6;
+/// How long to wait before trying to connect again after failing to.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// How long connecting to, or writing to, a remote endpoint may take before giving up on it.
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Syslog facility names, in order of their codes.
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+lazy_static::lazy_static! {
+    static ref HOSTNAME: String = fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_owned())
+        .unwrap_or("-".to_owned());
+}
+
+/// Look up a facility's code by its name, e.g. `local0`.
+pub fn facility(name: &str) -> Option<u8> {
+    FACILITIES.iter().position(|f| *f == name).map(|i| i as u8)
+}
+
+/// Where forwarded lines are sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    Local,       // The local syslog daemon, at `/dev/log`.
+    Udp(String), // A `host:port` to send datagrams to, one line per datagram (RFC 5426).
+    Tcp(String), // A `host:port` to stream lines to, octet-counted (RFC 6587).
+}
+impl Target {
+    /// Parse a forward target: `"syslog"`, `"udp://host:port"` or `"tcp://host:port"`.
+    pub fn parse(target: &str) -> Option<Self> {
+        match target.split_once("://") {
+            None if target == "syslog" => Some(Target::Local),
+            Some(("udp", address)) => Some(Target::Udp(address.to_owned())),
+            Some(("tcp", address)) => Some(Target::Tcp(address.to_owned())),
+            _ => None,
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        Ok(match self {
+            Target::Local => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(SYSLOG_SOCKET)?;
+                socket.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+                Connection::Local(socket)
+            }
+            Target::Udp(address) => {
+                let address = resolve(address)?;
+                let socket = UdpSocket::bind(if address.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                })?;
+                socket.connect(address)?;
+                Connection::Udp(socket)
+            }
+            Target::Tcp(address) => {
+                let stream = TcpStream::connect_timeout(&resolve(address)?, NETWORK_TIMEOUT)?;
+                stream.set_write_timeout(Some(NETWORK_TIMEOUT))?;
+                Connection::Tcp(stream)
+            }
+        })
+    }
+}
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Local => write!(f, "syslog"),
+            Target::Udp(address) => write!(f, "udp://{address}"),
+            Target::Tcp(address) => write!(f, "tcp://{address}"),
+        }
+    }
+}
+
+fn resolve(address: &str) -> io::Result<std::net::SocketAddr> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("couldn't resolve {address}"),
+        )
+    })
+}
+
+enum Connection {
+    Local(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+impl Connection {
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Connection::Local(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Connection::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Connection::Tcp(stream) => {
+                stream.write_all(format!("{} {message}", message.len()).as_bytes())
+            }
+        }
+    }
+}
+
+/// Format a captured line as an RFC 5424 syslog message.
+///
+/// stdout is logged at the `info` severity and stderr at `err`.
+pub fn format(line: &LogLine, facility: u8, tag: &str) -> String {
+    let severity = match line.stream {
+        Stream::Stdout => SEVERITY_INFO,
+        Stream::Stderr => SEVERITY_ERROR,
+    };
+
+    format!(
+        "<{}>1 {} {} {} - - - {}",
+        facility as u16 * 8 + severity as u16,
+        line.time.to_rfc3339_opts(SecondsFormat::Micros, true),
+        *HOSTNAME,
+        app_name(tag),
+        line.line
+    )
+}
+
+/// APP-NAME can't contain spaces, and is at most 48 characters.
+fn app_name(tag: &str) -> String {
+    tag.replace(' ', "_").chars().take(48).collect()
+}
+
+/// Forwards a service's captured lines to a target from a background thread.
+///
+/// Lines are buffered (up to `logs.forward_buffer` of them) while the target catches up. Once the
+/// buffer is full, new lines are dropped rather than holding up the service's output.
+#[derive(Clone)]
+pub struct Forwarder {
+    sender: SyncSender<LogLine>,
+    dropped: Arc<AtomicU64>,
+}
+impl Forwarder {
+    pub fn start(target: Target, facility: u8, tag: String) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(SETTINGS.logs.forward_buffer);
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let forwarder_dropped = dropped.clone();
+        thread::spawn(move || forward(receiver, target, facility, tag, forwarder_dropped));
+
+        Self { sender, dropped }
+    }
+
+    pub fn send(&self, line: &LogLine) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(line.clone()) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn forward(
+    receiver: Receiver<LogLine>,
+    target: Target,
+    facility: u8,
+    tag: String,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut connection: Option<Connection> = None;
+    let mut last_attempt: Option<Instant> = None;
+
+    for line in receiver {
+        let retry = last_attempt.is_none_or(|attempt| attempt.elapsed() >= RECONNECT_DELAY);
+        if connection.is_none() && retry {
+            last_attempt = Some(Instant::now());
+            connection = target
+                .connect()
+                .map_err(|e| error!(tag, "Couldn't connect to {target} to forward logs: {e}"))
+                .ok();
+        }
+
+        let Some(ref mut socket) = connection else {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        if let Err(e) = socket.send(&format(&line, facility, &tag)) {
+            error!(tag, "Couldn't forward logs to {target}: {e}");
+            dropped.fetch_add(1, Ordering::Relaxed);
+            // Reconnect; the other end may have restarted.
+            connection = None;
+            continue;
+        }
+
+        let lost = dropped.swap(0, Ordering::Relaxed);
+        if lost > 0 {
+            warn!(tag, "Dropped {lost} lines forwarding logs to {target}");
+        }
+    }
+}
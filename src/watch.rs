@@ -0,0 +1,97 @@
+use crate::services::{Service, ServiceConfiguration};
+use crate::{logging, RUNNING};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use parking_lot::RwLock;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Turn watch mode on or off for a service, starting its watcher the first time it's turned on.
+pub fn set(s: &Arc<RwLock<Service>>, enabled: bool) {
+    let start_watcher = enabled && !s.read().watcher_started;
+
+    s.write().watching = enabled;
+
+    if start_watcher {
+        s.write().watcher_started = true;
+
+        let s = s.clone();
+        thread::spawn(move || {
+            let _span = logging::span(&s.read().configuration).entered();
+            if let Err(e) = watch(s.clone()) {
+                error!("Couldn't watch for changes: {e}");
+                s.write().watcher_started = false;
+            }
+        });
+    }
+}
+
+/// Whether two configurations of a service watch the same things the same way.
+pub fn same_paths(a: &ServiceConfiguration, b: &ServiceConfiguration) -> bool {
+    a.wd == b.wd
+        && a.watch_paths == b.watch_paths
+        && a.ignore == b.ignore
+        && a.watch_debounce == b.watch_debounce
+}
+
+fn ignore_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+/// Whether an event should restart the service: it changes something, and not only ignored paths.
+fn is_relevant(event: &Event, wd: &Path, ignore: &GlobSet) -> bool {
+    let changes = matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+    );
+
+    changes
+        && event
+            .paths
+            .iter()
+            .any(|path| !ignore.is_match(path.strip_prefix(wd).unwrap_or(path)))
+}
+
+/// Watch a service's paths, restarting it on changes while watch mode is on.
+///
+/// Changes are debounced: the restart happens once nothing has changed for `watch_debounce`
+/// milliseconds, so a rebuild writing many files only restarts the service once.
+fn watch(s: Arc<RwLock<Service>>) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let conf = s.read().configuration.clone();
+    let ignore = ignore_set(&conf.ignore)?;
+    let debounce = Duration::from_millis(conf.watch_debounce);
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for path in conf.watch_paths.iter() {
+        watcher.watch(&conf.wd.join(path), RecursiveMode::Recursive)?;
+    }
+
+    info!("Watching for changes");
+
+    // Until the service is reconfigured to watch something else.
+    while RUNNING.load(Ordering::Relaxed) && same_paths(&s.read().configuration, &conf) {
+        match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(Ok(event)) if s.read().watching && is_relevant(&event, &conf.wd, &ignore) => {
+                // Wait for things to settle down
+                while rx.recv_timeout(debounce).is_ok() {}
+
+                info!("Change detected; restarting");
+                Service::restart(&s);
+            }
+            Ok(Err(e)) => error!("Watch error: {e}"),
+            Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    Ok(())
+}
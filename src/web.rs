@@ -1,67 +1,971 @@
-use crate::services::Service;
-use crate::{sysinfo_wrappers, SERVICES};
+use crate::auth::{Admin, Control, Granted, Read, Scope};
+use crate::crashes::{self, CrashReport};
+use crate::events::{self, Event};
+use crate::logs::LogLine;
+use crate::services::{Service, ServiceConfiguration, ServiceState};
+use crate::subscriptions::{self, Subscriptions};
+use crate::{
+    logs, metrics, settings, sysinfo_wrappers, watch, DEFAULT_CONFIG_PATH, PORT_ROCKET, SETTINGS,
+};
+use chrono::DateTime;
 use parking_lot::RwLock;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::http::Status;
+use rocket::http::{ContentType, Header};
+use rocket::request::{self, FromRequest};
+use rocket::response::stream::{self as sse, EventStream, TextStream};
+use rocket::response::{self, Responder};
 use rocket::serde::json::Json;
-use rocket::{get, routes, State};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast;
+use rocket::{catch, catchers, delete, get, patch, post, put, routes, Request, Response, Route};
 use rocket_ws as ws;
-use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::io::Cursor;
 use std::sync::Arc;
-use sysinfo::{NetworkExt, NetworksExt, ProcessExt, System, SystemExt};
+use std::time::{Duration, Instant};
+use tracing::warn;
 
-fn system_internal(sys_info: &State<RwLock<System>>) -> sysinfo_wrappers::System {
-    sys_info.write().refresh_all();
-    sys_info.write().refresh_networks();
-    sys_info.write().refresh_disks_list();
-    sys_info.write().refresh_disks();
+/// Where the current version of the API is mounted. It's also mounted at `/`, deprecated.
+pub const API_V1: &str = "/api/v1";
 
-    sysinfo_wrappers::System::init(sys_info.inner())
+/// How long to wait for a service to stop before killing it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a killed service to be reaped before giving up on it.
+const KILL_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn services_internal(scope: Scope) -> Vec<Service> {
+    crate::services::all()
+        .iter()
+        .map(|s| shown(s, scope))
+        .collect()
 }
-fn services_internal() -> Vec<Service> {
-    SERVICES.iter().map(|s| s.read().clone()).collect()
+
+/// A service as a client with a scope may see it: only admins see the secrets in its environment
+/// and arguments.
+pub fn shown(service: &Arc<RwLock<Service>>, scope: Scope) -> Service {
+    let service = service.read().clone();
+    match scope {
+        Scope::Admin => service,
+        _ => service.redacted(),
+    }
 }
 
 #[get("/")]
 pub fn index() -> String {
-    format!("Hello, world!")
+    "Hello, world!".to_string()
+}
+
+/// The host's latest metrics: every section and process, unless only some are asked for.
+///
+/// `section` (repeatable) is one of `host`, `memory`, `cpus`, `disks` or `processes`. Processes
+/// can be filtered by `name` (a substring), `user` and `pid` (repeatable), sorted by `cpu` or
+/// `memory`, paged with `offset` and `limit`, and have fields left out with `exclude`
+/// (repeatable). `process_count` says how many processes matched across every page.
+#[allow(clippy::too_many_arguments)]
+#[get("/system?<section>&<name>&<user>&<pid>&<sort>&<offset>&<limit>&<exclude>")]
+pub fn system(
+    _auth: Admin,
+    section: Vec<&str>,
+    name: Option<&str>,
+    user: Option<&str>,
+    pid: Vec<u32>,
+    sort: Option<&str>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    exclude: Vec<&str>,
+) -> Result<(ContentType, String), ApiError> {
+    let invalid = |what: &str, value: &str| {
+        ApiError::new(
+            Status::UnprocessableEntity,
+            format!("unknown {what} {value}"),
+        )
+    };
+
+    let sections = section
+        .iter()
+        .map(|s| sysinfo_wrappers::Section::parse(s).ok_or_else(|| invalid("section", s)))
+        .collect::<Result<_, _>>()?;
+    let sort = sort
+        .map(|s| sysinfo_wrappers::SortBy::parse(s).ok_or_else(|| invalid("sort", s)))
+        .transpose()?;
+    if let Some(field) = exclude
+        .iter()
+        .find(|f| !sysinfo_wrappers::PROCESS_FIELDS.contains(f))
+    {
+        return Err(invalid("process field", field));
+    }
+
+    let selection = sysinfo_wrappers::Selection {
+        sections,
+        name: name.map(str::to_owned),
+        user: user.map(str::to_owned),
+        pids: pid,
+        sort,
+        offset: offset.unwrap_or(0),
+        limit,
+        exclude: exclude.into_iter().map(str::to_owned).collect(),
+    };
+    let system = metrics::latest();
+    let body = serde_json::to_string(&system.select(&selection))
+        .map_err(|e| ApiError::new(Status::InternalServerError, e.to_string()))?;
+
+    Ok((ContentType::JSON, body))
 }
 
-#[get("/system")]
-pub fn system(sys_info: &State<RwLock<System>>) -> Json<sysinfo_wrappers::System> {
-    Json(system_internal(sys_info))
+/// Services' and the host's metrics in the Prometheus text format.
+#[get("/metrics")]
+pub fn prometheus_metrics(_auth: Read) -> (ContentType, String) {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    (content_type, metrics::prometheus())
 }
 
 #[get("/services")]
-pub fn services() -> Json<Vec<Service>> {
-    Json(services_internal())
+pub fn services(_auth: Read, granted: Granted) -> Json<Vec<Service>> {
+    Json(services_internal(granted.0))
 }
 
-#[get("/ws")]
-fn websocket<'a>(sys_info: &'a State<RwLock<System>>, ws: ws::WebSocket) -> ws::Stream!['a] {
-    ws::Stream! { ws =>
-        for await message in ws {
-            if let Ok(ws::Message::Text(ref text)) = message {
-                if text == "ping" {
-                    yield ws::Message::Text("pong".to_string());
-                } else if text == "system" {
-                    yield ws::Message::Text(json!(system_internal(sys_info)).to_string());
-                } else if text == "services" {
-                    yield ws::Message::Text(json!(services_internal()).to_string());
+#[post("/services/<id>/watch?<enabled>")]
+pub fn set_watch(
+    _auth: Control,
+    granted: Granted,
+    id: usize,
+    enabled: bool,
+) -> Option<Json<Service>> {
+    let service = crate::services::find(id)?;
+    watch::set(&service, enabled);
+
+    Some(Json(shown(&service, granted.0)))
+}
+
+/// An error response, as JSON like `{"status": 404, "error": "no service with id 7"}`.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub message: String,
+}
+impl ApiError {
+    pub fn new(status: Status, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = json!({ "status": self.status.code, "error": self.message });
+        Response::build_from(body.respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+/// Responds to requests without valid credentials, asking for basic auth if users can log in.
+pub struct Unauthorized;
+impl<'r> Responder<'r, 'static> for Unauthorized {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let error = ApiError::new(Status::Unauthorized, "a valid token or login is needed");
+        let mut response = error.respond_to(request)?;
+        if !SETTINGS.auth.users.is_empty() {
+            response.set_header(Header::new("WWW-Authenticate", "Basic realm=\"pmrs\""));
+        }
+
+        Ok(response)
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized() -> Unauthorized {
+    Unauthorized
+}
+
+#[catch(403)]
+pub fn forbidden() -> ApiError {
+    ApiError::new(
+        Status::Forbidden,
+        "this needs a token or login with more scope",
+    )
+}
+
+#[catch(404)]
+pub fn not_found(request: &Request) -> ApiError {
+    let path = request.uri().path();
+    ApiError::new(Status::NotFound, format!("nothing found at {path}"))
+}
+
+#[catch(422)]
+pub fn unprocessable_entity() -> ApiError {
+    ApiError::new(
+        Status::UnprocessableEntity,
+        "the request's parameters or body aren't valid",
+    )
+}
+
+#[catch(500)]
+pub fn internal_server_error() -> ApiError {
+    ApiError::new(
+        Status::InternalServerError,
+        "something went wrong handling the request",
+    )
+}
+
+#[catch(default)]
+pub fn other_error(status: Status, _request: &Request) -> ApiError {
+    ApiError::new(status, status.reason_lossy().to_lowercase())
+}
+
+/// Wraps JSON responses from the versioned API in an envelope, and marks responses from the
+/// unversioned routes as deprecated.
+///
+/// Successful responses become `{"ok": true, "data": ...}` and errors become `{"ok": false,
+/// "error": {"status": 404, "code": "not_found", "message": "..."}}`. Streams, websockets and
+/// metrics aren't JSON documents, so they're left as they are.
+pub struct Versioning;
+
+#[rocket::async_trait]
+impl Fairing for Versioning {
+    fn info(&self) -> Info {
+        Info {
+            name: "API versioning",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let path = request.uri().path();
+        let Some(route) = path.as_str().strip_prefix(API_V1) else {
+            if request.route().is_some() && path != "/" {
+                response.set_raw_header("Deprecation", "true");
+                response.set_raw_header(
+                    "Link",
+                    format!("<{API_V1}{path}>; rel=\"successor-version\""),
+                );
+            }
+            return;
+        };
+        if !route.is_empty() && !route.starts_with('/') {
+            return;
+        }
+        if response.content_type() != Some(ContentType::JSON) {
+            return;
+        }
+
+        let Ok(body) = response.body_mut().to_string().await else {
+            return;
+        };
+        let status = response.status();
+        let envelope = match status.class().is_success() {
+            // Spliced in as it is, so objects keep their order (like sorted processes).
+            true => format!(r#"{{"ok":true,"data":{body}}}"#),
+            false => {
+                let body: Value = serde_json::from_str(&body).unwrap_or_default();
+                let reason = status.reason_lossy();
+                let message = body.get("error").and_then(|e| e.as_str()).unwrap_or(reason);
+                json!({
+                    "ok": false,
+                    "error": {
+                        "status": status.code,
+                        "code": reason.to_lowercase().replace([' ', '-'], "_"),
+                        "message": message,
+                    },
+                })
+                .to_string()
+            }
+        };
+
+        response.set_sized_body(envelope.len(), Cursor::new(envelope));
+    }
+}
+
+fn find_service(id: usize) -> Result<Arc<RwLock<Service>>, ApiError> {
+    crate::services::find(id)
+        .ok_or_else(|| ApiError::new(Status::NotFound, format!("no service with id {id}")))
+}
+
+/// Wait for a service being stopped to stop, killing it if it takes too long.
+async fn stopped(service: &Arc<RwLock<Service>>) -> Result<(), ApiError> {
+    let wait = |timeout| async move {
+        let deadline = Instant::now() + timeout;
+        while service.read().state != ServiceState::Stopped {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        true
+    };
+
+    if wait(STOP_TIMEOUT).await {
+        return Ok(());
+    }
+    Service::signal(service, libc::SIGKILL);
+    if wait(KILL_TIMEOUT).await {
+        return Ok(());
+    }
+
+    Err(ApiError::new(
+        Status::GatewayTimeout,
+        "the service didn't stop, even after being killed",
+    ))
+}
+
+#[post("/services/<id>/start")]
+pub fn start_service(
+    _auth: Control,
+    granted: Granted,
+    id: usize,
+) -> Result<Json<Service>, ApiError> {
+    let service = find_service(id)?;
+    if !Service::start(&service) {
+        return Err(ApiError::new(Status::Conflict, "the service isn't stopped"));
+    }
+
+    Ok(Json(shown(&service, granted.0)))
+}
+
+/// Stop a service, responding once it has stopped. It's killed if it doesn't stop in time.
+#[post("/services/<id>/stop")]
+pub async fn stop_service(
+    _auth: Control,
+    granted: Granted,
+    id: usize,
+) -> Result<Json<Service>, ApiError> {
+    let service = find_service(id)?;
+    if !Service::stop(&service) {
+        return Err(ApiError::new(
+            Status::Conflict,
+            "the service is already stopped",
+        ));
+    }
+    stopped(&service).await?;
+
+    Ok(Json(shown(&service, granted.0)))
+}
+
+#[post("/services/<id>/restart")]
+pub fn restart_service(
+    _auth: Control,
+    granted: Granted,
+    id: usize,
+) -> Result<Json<Service>, ApiError> {
+    let service = find_service(id)?;
+    if !Service::restart(&service) {
+        return Err(ApiError::new(Status::Conflict, "the service isn't running"));
+    }
+
+    Ok(Json(shown(&service, granted.0)))
+}
+
+/// Ask a service to reload its configuration, by sending it `SIGHUP`.
+#[post("/services/<id>/reload")]
+pub fn reload_service(
+    _auth: Control,
+    granted: Granted,
+    id: usize,
+) -> Result<Json<Service>, ApiError> {
+    let service = find_service(id)?;
+    if !Service::signal(&service, libc::SIGHUP) {
+        return Err(ApiError::new(Status::Conflict, "the service isn't running"));
+    }
+
+    Ok(Json(shown(&service, granted.0)))
+}
+
+#[post("/services/<id>/signal/<signal>")]
+pub fn signal_service(
+    _auth: Control,
+    granted: Granted,
+    id: usize,
+    signal: &str,
+) -> Result<Json<Service>, ApiError> {
+    let service = find_service(id)?;
+    let number = crate::services::signal_number(signal).ok_or_else(|| {
+        ApiError::new(
+            Status::UnprocessableEntity,
+            format!("unknown signal {signal}"),
+        )
+    })?;
+    if !Service::signal(&service, number) {
+        return Err(ApiError::new(Status::Conflict, "the service isn't running"));
+    }
+
+    Ok(Json(shown(&service, granted.0)))
+}
+
+/// Add a service, defined as it would be under `[services.<name>]` in the config file (with its
+/// `name` too), and start it. With `persist`, it's also added to the config file.
+#[post("/services?<persist>", data = "<definition>")]
+pub fn create_service(
+    _auth: Admin,
+    definition: Json<toml::Table>,
+    persist: Option<bool>,
+) -> Result<Json<Service>, ApiError> {
+    let mut definition = definition.into_inner();
+    let name = match definition.remove("name") {
+        Some(toml::Value::String(name)) => name,
+        _ => {
+            return Err(ApiError::new(
+                Status::UnprocessableEntity,
+                "a service needs a name",
+            ))
+        }
+    };
+
+    let configuration = ServiceConfiguration::parse(&name, &definition, None)
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+    let service = Service::add(configuration)
+        .map_err(|e| ApiError::new(Status::InternalServerError, e.to_string()))?;
+    if persist.unwrap_or(false) {
+        settings::save_service(*DEFAULT_CONFIG_PATH, &name, &definition, None)
+            .map_err(|e| not_saved(&format!("added {name}"), e))?;
+    }
+
+    let service = service.read().clone();
+    Ok(Json(service))
+}
+
+/// Replace a service's definition. If it's running, it's restarted with the new one.
+#[put("/services/<id>?<persist>", data = "<definition>")]
+pub async fn replace_service(
+    _auth: Admin,
+    id: usize,
+    definition: Json<toml::Table>,
+    persist: Option<bool>,
+) -> Result<Json<Service>, ApiError> {
+    let service = find_service(id)?;
+    reconfigure(&service, definition.into_inner(), persist.unwrap_or(false)).await
+}
+
+/// Change some of a service's definition, keeping the rest. If it's running, it's restarted.
+#[patch("/services/<id>?<persist>", data = "<changes>")]
+pub async fn update_service(
+    _auth: Admin,
+    id: usize,
+    changes: Json<toml::Table>,
+    persist: Option<bool>,
+) -> Result<Json<Service>, ApiError> {
+    let service = find_service(id)?;
+    let mut definition = service.read().configuration.definition.clone();
+    definition.extend(changes.into_inner());
+    reconfigure(&service, definition, persist.unwrap_or(false)).await
+}
+
+/// A change that was applied, but couldn't be saved to the config file, so won't survive a restart.
+fn not_saved(change: &str, error: Box<dyn std::error::Error + 'static>) -> ApiError {
+    ApiError::new(
+        Status::InternalServerError,
+        format!("{change}, but couldn't save the change to the config file: {error}"),
+    )
+}
+
+async fn reconfigure(
+    service: &Arc<RwLock<Service>>,
+    mut definition: toml::Table,
+    persist: bool,
+) -> Result<Json<Service>, ApiError> {
+    let previous = service.read().configuration.name.clone();
+    let name = match definition.remove("name") {
+        Some(toml::Value::String(name)) => name,
+        Some(_) => {
+            return Err(ApiError::new(
+                Status::UnprocessableEntity,
+                "name must be a str",
+            ))
+        }
+        None => previous.clone(),
+    };
+
+    let configuration = ServiceConfiguration::parse(&name, &definition, Some(service))
+        .map_err(|e| ApiError::new(Status::UnprocessableEntity, e))?;
+
+    let running = Service::stop(service);
+    // Never swap the configuration out from under a process that's still running.
+    stopped(service).await?;
+    if let Err(e) = Service::replace(service, configuration, running) {
+        if running {
+            Service::start(service);
+        }
+        return Err(ApiError::new(Status::InternalServerError, e.to_string()));
+    }
+    if persist {
+        settings::save_service(*DEFAULT_CONFIG_PATH, &name, &definition, Some(&previous))
+            .map_err(|e| not_saved(&format!("changed {name}"), e))?;
+    }
+
+    let service = service.read().clone();
+    Ok(Json(service))
+}
+
+/// Stop a service and stop managing it, responding with its final state. With `persist`, it's also
+/// removed from the config file.
+#[delete("/services/<id>?<persist>")]
+pub async fn delete_service(
+    _auth: Admin,
+    id: usize,
+    persist: Option<bool>,
+) -> Result<Json<Service>, ApiError> {
+    let service = find_service(id)?;
+    Service::remove(&service);
+    stopped(&service).await?;
+    if persist.unwrap_or(false) {
+        let name = service.read().configuration.name.clone();
+        settings::remove_service(*DEFAULT_CONFIG_PATH, &name)
+            .map_err(|e| not_saved(&format!("removed {name}"), e))?;
+    }
+
+    let service = service.read().clone();
+    Ok(Json(service))
+}
+
+#[post("/services/<id>/rebuild")]
+pub async fn rebuild(
+    _auth: Control,
+    granted: Granted,
+    id: usize,
+) -> Result<Json<Service>, ApiError> {
+    let service = find_service(id)?;
+
+    if service.read().configuration.build.is_none() {
+        return Err(ApiError::new(
+            Status::UnprocessableEntity,
+            "the service has no build command",
+        ));
+    }
+    if service.read().building {
+        return Err(ApiError::new(
+            Status::Conflict,
+            "the service is already being built",
+        ));
+    }
+
+    rocket::tokio::task::spawn_blocking({
+        let service = service.clone();
+        move || Service::rebuild(&service)
+    })
+    .await
+    .map_err(|e| ApiError::new(Status::InternalServerError, e.to_string()))?
+    .map_err(|e| {
+        ApiError::new(
+            Status::InternalServerError,
+            format!("couldn't run the build: {e}"),
+        )
+    })?;
+
+    Ok(Json(shown(&service, granted.0)))
+}
+
+#[post("/logs/rotate?<service>")]
+pub fn rotate_logs(_auth: Control, service: Vec<usize>) -> Result<Json<Vec<String>>, ApiError> {
+    let names = match service.is_empty() {
+        true => Vec::new(), // Rotates every service's log.
+        false => log_services(&service)?
+            .into_iter()
+            .map(|c| c.name)
+            .collect(),
+    };
+
+    let rotated = logs::rotate(&names).map_err(|e| {
+        ApiError::new(
+            Status::InternalServerError,
+            format!("couldn't rotate the logs: {e}"),
+        )
+    })?;
+
+    Ok(Json(
+        rotated
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect(),
+    ))
+}
+
+/// The configurations of the services with the given ids, or of every service if none are given.
+fn log_services(ids: &[usize]) -> Result<Vec<ServiceConfiguration>, ApiError> {
+    if ids.is_empty() {
+        return Ok(crate::services::all()
+            .iter()
+            .map(|s| s.read().configuration.clone())
+            .collect());
+    }
+
+    ids.iter()
+        .map(|id| Ok(find_service(*id)?.read().configuration.clone()))
+        .collect()
+}
+
+fn log_filter(
+    since: Option<&str>,
+    until: Option<&str>,
+    stderr_only: bool,
+    grep: Option<&str>,
+) -> Result<logs::LogFilter, ApiError> {
+    let time = |time: Option<&str>| {
+        time.map(|time| {
+            DateTime::parse_from_rfc3339(time)
+                .map(|t| t.into())
+                .map_err(|e| {
+                    ApiError::new(
+                        Status::UnprocessableEntity,
+                        format!("invalid time {time}: {e}"),
+                    )
+                })
+        })
+        .transpose()
+    };
+
+    Ok(logs::LogFilter {
+        since: time(since)?,
+        until: time(until)?,
+        stderr_only,
+        grep: grep.map(regex::Regex::new).transpose().map_err(|e| {
+            ApiError::new(Status::UnprocessableEntity, format!("invalid regex: {e}"))
+        })?,
+    })
+}
+
+/// Captured output of the given services (all by default), as JSON lines.
+///
+/// With `follow`, the response stays open and new lines are streamed as they're written.
+#[allow(clippy::too_many_arguments)]
+#[get("/logs?<service>&<n>&<since>&<stderr_only>&<grep>&<follow>")]
+pub fn tail_logs(
+    _auth: Read,
+    service: Vec<usize>,
+    n: Option<usize>,
+    since: Option<&str>,
+    stderr_only: bool,
+    grep: Option<&str>,
+    follow: bool,
+) -> Result<TextStream![String], ApiError> {
+    let services = log_services(&service)?;
+
+    let filter = log_filter(since, None, stderr_only, grep)?;
+
+    let mut follower = logs::Follower::new(&services);
+    let lines = logs::tail(&services, &filter, n.unwrap_or(10));
+
+    Ok(TextStream! {
+        for line in lines {
+            yield format!("{}\n", json!(line));
+        }
+
+        if follow {
+            loop {
+                for line in follower.poll(&filter) {
+                    yield format!("{}\n", json!(line));
                 }
+                rocket::tokio::time::sleep(Duration::from_millis(250)).await;
+            }
+        }
+    })
+}
+
+/// Search the given services' logs (all by default), including rotated ones, for lines matching
+/// a regex within a time range.
+#[allow(clippy::too_many_arguments)]
+#[get("/logs/search?<service>&<q>&<from>&<to>&<stderr_only>&<page>&<per_page>")]
+pub async fn search_logs(
+    _auth: Read,
+    service: Vec<usize>,
+    q: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    stderr_only: bool,
+    page: Option<usize>,
+    per_page: Option<usize>,
+) -> Result<Json<logs::SearchResults>, ApiError> {
+    let services = log_services(&service)?;
+
+    let filter = log_filter(from, to, stderr_only, q)?;
+    let (page, per_page) = (
+        page.unwrap_or(1).max(1),
+        per_page.unwrap_or(100).clamp(1, 1000),
+    );
+
+    let results = rocket::tokio::task::spawn_blocking(move || {
+        logs::search(&services, &filter, page, per_page)
+    })
+    .await
+    .map_err(|e| ApiError::new(Status::InternalServerError, e.to_string()))?
+    .map_err(|e| {
+        ApiError::new(
+            Status::InternalServerError,
+            format!("couldn't search the logs: {e}"),
+        )
+    })?;
+
+    Ok(Json(results))
+}
+
+/// Recent service events, oldest first.
+#[get("/events")]
+pub fn recent_events(_auth: Read) -> Json<Vec<Event>> {
+    Json(events::recent())
+}
+
+/// The id of the last event a client saw, from the `Last-Event-ID` header browsers send when they
+/// reconnect to an event stream.
+pub struct LastEventId(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let id = request
+            .headers()
+            .get_one("Last-Event-ID")
+            .map(|id| id.to_owned());
+        request::Outcome::Success(LastEventId(id))
+    }
+}
+
+/// Service events as server-sent events, for the given services (all by default).
+///
+/// Events after `Last-Event-ID` (or `last_event_id`) that are still in the history are sent first,
+/// so a client that reconnects doesn't miss any.
+#[get("/events/stream?<service>&<last_event_id>")]
+pub fn event_stream(
+    _auth: Read,
+    service: Vec<usize>,
+    last_event_id: Option<&str>,
+    header: LastEventId,
+) -> EventStream![] {
+    let after = header.0.as_deref().or(last_event_id).map(events::seen);
+    let wanted = move |event: &Event| service.is_empty() || service.contains(&event.service_id);
+
+    // Subscribe first so nothing's missed between the history and live events.
+    let mut live = events::subscribe();
+    let missed: Vec<Event> = match after {
+        Some(after) => events::recent()
+            .into_iter()
+            .filter(|e| e.id > after)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    EventStream! {
+        let mut last = after;
+        for event in missed.into_iter().filter(&wanted) {
+            last = Some(event.id);
+            yield sse(&event);
+        }
+
+        loop {
+            let event = match live.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if last.is_none_or(|last| event.id > last) && wanted(&event) {
+                yield sse(&event);
             }
         }
     }
 }
 
+fn sse(event: &Event) -> sse::Event {
+    let data = json!(event);
+    let name = data["event"].as_str().unwrap_or("event").to_owned();
+    sse::Event::json(&data)
+        .id(events::cursor(event.id))
+        .event(name)
+}
+
+/// A service's crash reports, newest first.
+#[get("/services/<id>/crashes")]
+pub fn list_crashes(_auth: Read, id: usize) -> Result<Json<Vec<CrashReport>>, ApiError> {
+    let name = find_service(id)?.read().configuration.name.clone();
+
+    let reports = crashes::list(&name).map_err(|e| {
+        ApiError::new(
+            Status::InternalServerError,
+            format!("couldn't read the crash reports: {e}"),
+        )
+    })?;
+    Ok(Json(reports))
+}
+
+#[get("/services/<id>/crashes/<crash>")]
+pub fn get_crash(_auth: Read, id: usize, crash: &str) -> Result<Json<CrashReport>, ApiError> {
+    let name = find_service(id)?.read().configuration.name.clone();
+
+    crashes::get(&name, crash)
+        .map_err(|e| {
+            ApiError::new(
+                Status::InternalServerError,
+                format!("couldn't read crash report {crash}: {e}"),
+            )
+        })?
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError::new(
+                Status::NotFound,
+                format!("{name} has no crash report {crash}"),
+            )
+        })
+}
+
+/// A service's recent output, from memory.
+#[get("/services/<id>/output?<n>&<since>&<stderr_only>&<grep>")]
+pub fn output(
+    _auth: Read,
+    id: usize,
+    n: Option<usize>,
+    since: Option<&str>,
+    stderr_only: bool,
+    grep: Option<&str>,
+) -> Result<Json<Vec<LogLine>>, ApiError> {
+    let service = find_service(id)?;
+    let filter = log_filter(since, None, stderr_only, grep)?;
+
+    let lines = service.read().output.recent(&filter, n.unwrap_or(100));
+    Ok(Json(lines))
+}
+
+/// A service's recent output followed by new lines as they're captured, one JSON message per line.
+#[get("/services/<id>/output/ws?<n>")]
+fn output_websocket(
+    _auth: Read,
+    id: usize,
+    n: Option<usize>,
+    ws: ws::WebSocket,
+) -> Option<ws::Channel<'static>> {
+    let output = crate::services::find(id)?.read().output.clone();
+
+    Some(ws.channel(move |mut stream| {
+        Box::pin(async move {
+            // Subscribe first so nothing's missed between the backlog and live lines.
+            let mut live = output.subscribe();
+            let filter = logs::LogFilter::default();
+            for line in output.recent(&filter, n.unwrap_or(100)) {
+                stream
+                    .send(ws::Message::Text(json!(line).to_string()))
+                    .await?;
+            }
+
+            loop {
+                select! {
+                    line = live.recv() => match line {
+                        Ok(line) => stream.send(ws::Message::Text(json!(line).to_string())).await?,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    message = stream.next() => match message {
+                        Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    },
+                }
+            }
+
+            Ok(())
+        })
+    }))
+}
+
+/// Updates on the topics a client subscribes to, as they happen (see `subscriptions::Request`).
+///
+/// `"ping"`, `"system"` and `"services"` messages are answered once, as they were before
+/// subscriptions.
+#[get("/ws")]
+fn websocket(granted: Granted, ws: ws::WebSocket) -> ws::Channel<'static> {
+    let scope = granted.0;
+
+    ws.channel(move |mut stream| {
+        Box::pin(async move {
+            let (mut subscriptions, mut updates) = Subscriptions::new(scope);
+
+            loop {
+                select! {
+                    update = updates.recv() => match update {
+                        Some(update) => stream.send(ws::Message::Text(update)).await?,
+                        None => break,
+                    },
+                    message = stream.next() => match message {
+                        Some(Ok(ws::Message::Text(text))) => {
+                            let reply = match text.as_str() {
+                                "ping" => "pong".to_owned(),
+                                "system" if scope < Scope::Admin => {
+                                    json!({ "error": "system needs more scope" }).to_string()
+                                }
+                                "system" => json!(metrics::latest()).to_string(),
+                                "services" => json!(services_internal(scope)).to_string(),
+                                text => match subscriptions::Request::parse(text) {
+                                    Some(request) => subscriptions.handle(request).to_string(),
+                                    None => json!({ "error": "unknown message" }).to_string(),
+                                },
+                            };
+                            stream.send(ws::Message::Text(reply)).await?
+                        }
+                        Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    },
+                }
+            }
+
+            Ok(())
+        })
+    })
+}
+
 pub async fn rocket() -> Result<(), rocket::Error> {
-    let sys_info = RwLock::new(System::new_all());
+    let figment = rocket::Config::figment().merge(("port", *PORT_ROCKET));
+
+    if !SETTINGS.auth.enabled() {
+        warn!("No API tokens or users are configured; anyone who can reach the API can use it");
+    }
 
-    let _rocket = rocket::build()
-        .mount("/", routes![index, system, services, websocket])
+    let _rocket = rocket::custom(figment)
+        .mount("/", routes![index])
+        .mount("/", api())
+        .mount(API_V1, api())
+        .register(
+            "/",
+            catchers![
+                unauthorized,
+                forbidden,
+                not_found,
+                unprocessable_entity,
+                internal_server_error,
+                other_error
+            ],
+        )
+        .attach(Versioning)
         .manage(services)
-        .manage(sys_info)
         .launch()
         .await?;
 
     Ok(())
 }
+
+/// Every API route, to mount under each version.
+fn api() -> Vec<Route> {
+    routes![
+        system,
+        services,
+        prometheus_metrics,
+        set_watch,
+        start_service,
+        stop_service,
+        restart_service,
+        reload_service,
+        signal_service,
+        create_service,
+        replace_service,
+        update_service,
+        delete_service,
+        rebuild,
+        rotate_logs,
+        tail_logs,
+        search_logs,
+        recent_events,
+        event_stream,
+        output,
+        output_websocket,
+        list_crashes,
+        get_crash,
+        websocket
+    ]
+}